@@ -0,0 +1,412 @@
+use std::fmt::{self, Display};
+use std::io::{self, Read};
+use std::time::Duration;
+
+use crate::asc::{self, AscError, AudioSpecificConfig, SAMPLING_FREQUENCIES};
+use crate::framing::FrameReader;
+
+pub const HEADER_SIZE: usize = 7;
+pub const HEADER_SIZE_CRC: usize = 9;
+// 13 bit frame_length field
+pub const MAX_FRAME_LENGTH: usize = 0x1fff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdtsHeader {
+    // the ID bit, MPEG-2 instead of MPEG-4 AAC
+    pub mpeg2: bool,
+    pub has_crc: bool,
+    // audio object type minus one, 1 for AAC-LC
+    pub profile: u8,
+    pub sampling_frequency_index: u8,
+    pub channel_config: u8,
+    // header included
+    pub frame_length: usize,
+    // 0x7ff for VBR
+    pub buffer_fullness: u16,
+    pub raw_data_blocks: usize,
+}
+
+impl AdtsHeader {
+    // data has to hold the fixed and variable header, the CRC is not checked.
+    pub fn parse(data: &[u8]) -> Result<AdtsHeader, AdtsError> {
+        if data.len() < HEADER_SIZE {
+            return Err(AdtsError::TooShort);
+        }
+
+        // 12 bit syncword and layer 0
+        if data[0] != 0xff || data[1] & 0xf6 != 0xf0 {
+            return Err(AdtsError::NoSyncword);
+        }
+
+        let header = AdtsHeader {
+            mpeg2: data[1] & 0x08 != 0,
+            has_crc: data[1] & 0x01 == 0,
+            profile: data[2] >> 6,
+            sampling_frequency_index: (data[2] >> 2) & 0x0f,
+            channel_config: ((data[2] & 0x01) << 2) | (data[3] >> 6),
+            frame_length: ((data[3] as usize & 0x03) << 11) | ((data[4] as usize) << 3) | (data[5] as usize >> 5),
+            buffer_fullness: ((data[5] as u16 & 0x1f) << 6) | (data[6] as u16 >> 2),
+            raw_data_blocks: (data[6] as usize & 0x03) + 1,
+        };
+
+        if header.sample_rate().is_none() {
+            return Err(AdtsError::ReservedSampleRate(header.sampling_frequency_index));
+        }
+
+        if header.frame_length < header.header_length() {
+            return Err(AdtsError::InvalidFrameLength(header.frame_length));
+        }
+
+        Ok(header)
+    }
+
+    // Describes a frame carrying one raw access unit of payload_length bytes
+    // of the stream described by an AudioSpecificConfig, e.g.
+    // EncoderInfo::config. Only AAC Main, LC, SSR and LTP fit into ADTS,
+    // for HE-AAC the core is signaled and SBR is implicit.
+    pub fn from_config(config: &[u8], payload_length: usize) -> Result<AdtsHeader, AdtsError> {
+        let config = AudioSpecificConfig::parse(config)?;
+
+        let sampling_frequency_index = asc::sampling_frequency_index(config.sample_rate)
+            .ok_or(AdtsError::UnsupportedConfig)?;
+        if !(1..=4).contains(&config.aot) || config.channel_config > 7 || config.frame_length_flag {
+            return Err(AdtsError::UnsupportedConfig);
+        }
+
+        AdtsHeader {
+            mpeg2: false,
+            has_crc: false,
+            profile: config.aot as u8 - 1,
+            sampling_frequency_index,
+            channel_config: config.channel_config,
+            frame_length: HEADER_SIZE,
+            buffer_fullness: 0x7ff,
+            raw_data_blocks: 1,
+        }.with_payload_length(payload_length)
+    }
+
+    // The same header for a frame of one raw data block of payload_length
+    // bytes, without CRC.
+    pub fn with_payload_length(&self, payload_length: usize) -> Result<AdtsHeader, AdtsError> {
+        let frame_length = HEADER_SIZE + payload_length;
+        if frame_length > MAX_FRAME_LENGTH {
+            return Err(AdtsError::InvalidFrameLength(frame_length));
+        }
+
+        Ok(AdtsHeader {
+            has_crc: false,
+            frame_length,
+            raw_data_blocks: 1,
+            ..*self
+        })
+    }
+
+    // For Decoder::new_raw or MP4 storage of the payload. SBR and PS aren't
+    // signaled in ADTS, they stay implicit.
+    pub fn audio_specific_config(&self) -> Result<AudioSpecificConfig, AdtsError> {
+        let sample_rate = self.sample_rate()
+            .ok_or(AdtsError::ReservedSampleRate(self.sampling_frequency_index))?;
+
+        Ok(AudioSpecificConfig {
+            aot: self.aot() as u32,
+            ..AudioSpecificConfig::new(sample_rate, self.channel_config)
+        })
+    }
+
+    // Only headers without CRC are written: adts_error_check also covers
+    // bits of the raw data blocks, which aren't known here. A header parsed
+    // with has_crc has to go through with_payload_length first.
+    pub fn to_bytes(&self) -> Result<[u8; HEADER_SIZE], AdtsError> {
+        if self.has_crc {
+            return Err(AdtsError::CrcNotSupported);
+        }
+        if !(1..=4).contains(&self.raw_data_blocks) {
+            return Err(AdtsError::InvalidRawDataBlocks(self.raw_data_blocks));
+        }
+        if !(HEADER_SIZE..=MAX_FRAME_LENGTH).contains(&self.frame_length) {
+            return Err(AdtsError::InvalidFrameLength(self.frame_length));
+        }
+        if self.sample_rate().is_none() {
+            return Err(AdtsError::ReservedSampleRate(self.sampling_frequency_index));
+        }
+        if self.profile > 3 || self.channel_config > 7 {
+            return Err(AdtsError::UnsupportedConfig);
+        }
+
+        let len = self.frame_length;
+        let fullness = self.buffer_fullness as usize;
+
+        Ok([
+            0xff,
+            0xf1 | (self.mpeg2 as u8) << 3,
+            self.profile << 6 | self.sampling_frequency_index << 2 | self.channel_config >> 2,
+            (self.channel_config & 0x03) << 6 | (len >> 11) as u8,
+            (len >> 3) as u8,
+            ((len & 0x07) << 5 | (fullness >> 6) & 0x1f) as u8,
+            ((fullness & 0x3f) << 2 | (self.raw_data_blocks - 1)) as u8,
+        ])
+    }
+
+    pub fn header_length(&self) -> usize {
+        if self.has_crc { HEADER_SIZE_CRC } else { HEADER_SIZE }
+    }
+
+    pub fn payload_length(&self) -> usize {
+        self.frame_length - self.header_length()
+    }
+
+    // The MPEG-4 audio object type number.
+    pub fn aot(&self) -> u8 {
+        self.profile + 1
+    }
+
+    pub fn sample_rate(&self) -> Option<u32> {
+        SAMPLING_FREQUENCIES.get(self.sampling_frequency_index as usize).copied()
+    }
+
+    // Per channel, of the AAC core. SBR doubles this at the output.
+    pub fn samples(&self) -> usize {
+        self.raw_data_blocks * 1024
+    }
+}
+
+// Prepends an ADTS header to a raw access unit, see
+// AdtsHeader::from_config.
+pub fn wrap(config: &[u8], access_unit: &[u8]) -> Result<Vec<u8>, AdtsError> {
+    let header = AdtsHeader::from_config(config, access_unit.len())?;
+
+    let mut frame = Vec::with_capacity(header.frame_length);
+    frame.extend_from_slice(&header.to_bytes()?);
+    frame.extend_from_slice(access_unit);
+    Ok(frame)
+}
+
+#[derive(Debug, Clone)]
+pub struct AdtsFrame {
+    pub header: AdtsHeader,
+    // header included
+    pub data: Vec<u8>,
+}
+
+impl AdtsFrame {
+    pub fn payload(&self) -> &[u8] {
+        &self.data[self.header.header_length()..]
+    }
+}
+
+// Splits a byte stream into ADTS frames. A leading ID3v2 tag is skipped.
+// After a broken header it searches for the next syncword, only accepting
+// one that is followed by another syncword a frame length later.
+pub struct AdtsFrames<R> {
+    reader: FrameReader<R>,
+}
+
+impl<R: Read> AdtsFrames<R> {
+    pub fn new(input: R) -> Self {
+        AdtsFrames { reader: FrameReader::new(input) }
+    }
+
+    // Bytes dropped while resynchronizing, including a truncated last frame.
+    pub fn skipped_bytes(&self) -> u64 {
+        self.reader.skipped_bytes()
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader.into_inner()
+    }
+
+    fn next_frame(&mut self) -> io::Result<Option<AdtsFrame>> {
+        let data = self.reader.next_frame(HEADER_SIZE, |data| {
+            AdtsHeader::parse(data).ok().map(|header| header.frame_length)
+        })?;
+
+        Ok(data.map(|data| AdtsFrame {
+            header: AdtsHeader::parse(&data).expect("checked by the frame reader"),
+            data,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for AdtsFrames<R> {
+    type Item = io::Result<AdtsFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
+    }
+}
+
+// samples are per channel at the core sample rate, implicit SBR doubles
+// both without changing the duration. bytes counts whole frames only.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdtsScan {
+    pub frames: u64,
+    pub samples: u64,
+    pub sample_rate: u32,
+    pub bytes: u64,
+    pub skipped_bytes: u64,
+    pub duration: Duration,
+    pub bit_rate: u32,
+}
+
+// Walks the frame headers of a whole stream without decoding anything.
+// The sample rate is that of the first frame.
+pub fn scan<R: Read>(input: R) -> io::Result<AdtsScan> {
+    let mut frames = AdtsFrames::new(input);
+    let mut scan = AdtsScan {
+        frames: 0,
+        samples: 0,
+        sample_rate: 0,
+        bytes: 0,
+        skipped_bytes: 0,
+        duration: Duration::default(),
+        bit_rate: 0,
+    };
+
+    for frame in &mut frames {
+        let header = frame?.header;
+        if scan.frames == 0 {
+            scan.sample_rate = header.sample_rate().unwrap_or(0);
+        }
+
+        scan.frames += 1;
+        scan.samples += header.samples() as u64;
+        scan.bytes += header.frame_length as u64;
+    }
+    scan.skipped_bytes = frames.skipped_bytes();
+
+    if scan.sample_rate > 0 {
+        scan.duration = Duration::from_nanos((scan.samples as u128 * 1_000_000_000 / scan.sample_rate as u128) as u64);
+    }
+    if let Some(bit_rate) = (scan.bytes * 8 * scan.sample_rate as u64).checked_div(scan.samples) {
+        scan.bit_rate = bit_rate as u32;
+    }
+
+    Ok(scan)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdtsError {
+    TooShort,
+    NoSyncword,
+    InvalidFrameLength(usize),
+    // sampling_frequency_index 13 to 15
+    ReservedSampleRate(u8),
+    // only 1 to 4 fit into the header
+    InvalidRawDataBlocks(usize),
+    CrcNotSupported,
+    InvalidConfig,
+    UnsupportedConfig,
+}
+
+impl Display for AdtsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AdtsError::TooShort => write!(f, "ADTS header truncated"),
+            AdtsError::NoSyncword => write!(f, "no ADTS syncword"),
+            AdtsError::InvalidFrameLength(len) => write!(f, "invalid ADTS frame length {}", len),
+            AdtsError::ReservedSampleRate(index) => write!(f, "reserved ADTS sampling frequency index {}", index),
+            AdtsError::InvalidRawDataBlocks(blocks) => write!(f, "{} raw data blocks can't be signaled in ADTS", blocks),
+            AdtsError::CrcNotSupported => write!(f, "writing ADTS headers with CRC is not supported"),
+            AdtsError::InvalidConfig => write!(f, "truncated AudioSpecificConfig"),
+            AdtsError::UnsupportedConfig => write!(f, "audio object type or sample rate can't be signaled in ADTS"),
+        }
+    }
+}
+
+impl std::error::Error for AdtsError {
+}
+
+impl From<AscError> for AdtsError {
+    fn from(e: AscError) -> Self {
+        match e {
+            AscError::Truncated => AdtsError::InvalidConfig,
+            AscError::Unsupported => AdtsError::UnsupportedConfig,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: [u8; 2] = [0x12, 0x10];
+
+    fn stream(payload_lengths: &[usize]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (index, len) in payload_lengths.iter().enumerate() {
+            data.extend(wrap(&CONFIG, &vec![index as u8; *len]).unwrap());
+        }
+        data
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let header = AdtsHeader {
+            mpeg2: true,
+            buffer_fullness: 0x155,
+            ..AdtsHeader::from_config(&CONFIG, 300).unwrap()
+        };
+        assert_eq!(header.profile, 1);
+        assert_eq!(header.sample_rate(), Some(44100));
+        assert_eq!(header.frame_length, HEADER_SIZE + 300);
+
+        let bytes = header.to_bytes().unwrap();
+        assert_eq!(AdtsHeader::parse(&bytes), Ok(header));
+        assert_eq!(header.audio_specific_config().unwrap().to_bytes().unwrap(), CONFIG);
+    }
+
+    #[test]
+    fn parse_crc_header() {
+        let mut bytes = AdtsHeader::from_config(&CONFIG, 100).unwrap().to_bytes().unwrap();
+        bytes[1] &= !0x01;
+
+        let header = AdtsHeader::parse(&bytes).unwrap();
+        assert!(header.has_crc);
+        assert_eq!(header.payload_length(), 100 + HEADER_SIZE - HEADER_SIZE_CRC);
+        assert_eq!(header.to_bytes(), Err(AdtsError::CrcNotSupported));
+        assert!(header.with_payload_length(100).unwrap().to_bytes().is_ok());
+    }
+
+    #[test]
+    fn parse_rejects_reserved_sample_rate() {
+        let mut bytes = AdtsHeader::from_config(&CONFIG, 100).unwrap().to_bytes().unwrap();
+        bytes[2] |= 13 << 2;
+        assert_eq!(AdtsHeader::parse(&bytes), Err(AdtsError::ReservedSampleRate(13)));
+    }
+
+    #[test]
+    fn to_bytes_rejects_invalid_headers() {
+        let header = AdtsHeader::from_config(&CONFIG, 100).unwrap();
+        assert_eq!(AdtsHeader { raw_data_blocks: 0, ..header }.to_bytes(), Err(AdtsError::InvalidRawDataBlocks(0)));
+        assert_eq!(AdtsHeader { raw_data_blocks: 5, ..header }.to_bytes(), Err(AdtsError::InvalidRawDataBlocks(5)));
+        assert_eq!(AdtsHeader { frame_length: MAX_FRAME_LENGTH + 1, ..header }.to_bytes(),
+            Err(AdtsError::InvalidFrameLength(MAX_FRAME_LENGTH + 1)));
+        assert_eq!(AdtsHeader { sampling_frequency_index: 15, ..header }.to_bytes(), Err(AdtsError::ReservedSampleRate(15)));
+        assert_eq!(header.with_payload_length(MAX_FRAME_LENGTH), Err(AdtsError::InvalidFrameLength(MAX_FRAME_LENGTH + HEADER_SIZE)));
+    }
+
+    #[test]
+    fn frames_drop_truncated_tail() {
+        let mut data = stream(&[100, 200, 300]);
+        data.truncate(data.len() - 10);
+
+        let mut frames = AdtsFrames::new(&data[..]);
+        let payloads: Vec<Vec<u8>> = (&mut frames).map(|frame| frame.unwrap().payload().to_vec()).collect();
+        assert_eq!(payloads, [vec![0; 100], vec![1; 200]]);
+        assert_eq!(frames.skipped_bytes(), (HEADER_SIZE + 300 - 10) as u64);
+    }
+
+    #[test]
+    fn scan_totals() {
+        let data = stream(&[100, 200, 300, 400]);
+        let scan = scan(&data[..]).unwrap();
+
+        assert_eq!(scan.frames, 4);
+        assert_eq!(scan.samples, 4096);
+        assert_eq!(scan.sample_rate, 44100);
+        assert_eq!(scan.bytes, data.len() as u64);
+        assert_eq!(scan.skipped_bytes, 0);
+        assert_eq!(scan.duration, Duration::from_nanos(4096 * 1_000_000_000 / 44100));
+        assert_eq!(scan.bit_rate, (data.len() as u64 * 8 * 44100 / 4096) as u32);
+    }
+}
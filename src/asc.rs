@@ -0,0 +1,428 @@
+use std::fmt::{self, Display};
+
+// Indexed by sampling_frequency_index, 13 and 14 are reserved and 15 means
+// an explicit 24 bit rate follows.
+pub(crate) const SAMPLING_FREQUENCIES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+const AOT_SBR: u32 = 5;
+const AOT_PS: u32 = 29;
+const AOT_ER_BSAC: u32 = 22;
+const AOT_ER_AAC_ELD: u32 = 39;
+
+// syncExtensionType values of backward compatible signaling
+const SYNC_EXTENSION_SBR: u32 = 0x2b7;
+const SYNC_EXTENSION_PS: u32 = 0x548;
+
+// The MPEG-4 AudioSpecificConfig as found in EncoderInfo::config, the MP4
+// esds box, RTP fmtp config and Matroska CodecPrivate. aot is that of the
+// core coder, with SBR and PS reported separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioSpecificConfig {
+    pub aot: u32,
+    pub sample_rate: u32,
+    pub channel_config: u8,
+    pub sbr: bool,
+    pub ps: bool,
+    // output rate with SBR, otherwise equal to sample_rate
+    pub ext_sample_rate: u32,
+    // SBR and PS signaled after the core config, where decoders not
+    // knowing about them skip it, instead of up front
+    pub backward_compatible: bool,
+    // 960 (480 for LD/ELD) instead of 1024 (512) samples per frame
+    pub frame_length_flag: bool,
+}
+
+impl AudioSpecificConfig {
+    // A plain AAC-LC config.
+    pub fn new(sample_rate: u32, channel_config: u8) -> Self {
+        AudioSpecificConfig {
+            aot: 2,
+            sample_rate,
+            channel_config,
+            sbr: false,
+            ps: false,
+            ext_sample_rate: sample_rate,
+            backward_compatible: false,
+            frame_length_flag: false,
+        }
+    }
+
+    // Trailing data after the parts described here, e.g. a program config
+    // element or ELD/USAC specific configuration, is not parsed.
+    pub fn parse(data: &[u8]) -> Result<AudioSpecificConfig, AscError> {
+        AudioSpecificConfig::read(&mut BitReader::new(data), true).map(|(config, _)| config)
+    }
+
+    // Also returns whether the end of the config was reached, i.e. nothing
+    // was left unparsed. Without sync_extension the backward compatible
+    // signaling is not looked for, as when the length of the config is not
+    // known up front.
+    pub(crate) fn read(bits: &mut BitReader, sync_extension: bool) -> Result<(AudioSpecificConfig, bool), AscError> {
+        let mut aot = bits.object_type()?;
+        let sample_rate = bits.sample_rate()?;
+        let channel_config = bits.read(4)? as u8;
+
+        let mut config = AudioSpecificConfig {
+            aot,
+            sample_rate,
+            channel_config,
+            sbr: false,
+            ps: false,
+            ext_sample_rate: sample_rate,
+            backward_compatible: false,
+            frame_length_flag: false,
+        };
+
+        if aot == AOT_SBR || aot == AOT_PS {
+            config.sbr = true;
+            config.ps = aot == AOT_PS;
+            config.ext_sample_rate = bits.sample_rate()?;
+            aot = bits.object_type()?;
+            if aot == AOT_ER_BSAC {
+                bits.read(4)?;
+            }
+            config.aot = aot;
+        }
+
+        if is_general_audio(aot) {
+            config.frame_length_flag = bits.read(1)? != 0;
+            if bits.read(1)? != 0 {
+                // coreCoderDelay
+                bits.read(14)?;
+            }
+            let extension_flag = bits.read(1)? != 0;
+
+            // a program config element follows, which isn't parsed
+            if channel_config == 0 {
+                return Ok((config, false));
+            }
+
+            if aot == 6 || aot == 20 {
+                // layerNr
+                bits.read(3)?;
+            }
+            if extension_flag {
+                if aot == AOT_ER_BSAC {
+                    // numOfSubFrame and layer_length
+                    bits.read(16)?;
+                }
+                if [17, 19, 20, 23].contains(&aot) {
+                    // the resilience flags
+                    bits.read(3)?;
+                }
+                // extensionFlag3
+                bits.read(1)?;
+            }
+        } else if aot == AOT_ER_AAC_ELD {
+            config.frame_length_flag = bits.read(1)? != 0;
+            return Ok((config, false));
+        } else {
+            return Ok((config, false));
+        }
+
+        if (17..=27).contains(&aot) {
+            // epConfig
+            bits.read(2)?;
+        }
+
+        if sync_extension && !config.sbr && bits.remaining() >= 16 && bits.read(11)? == SYNC_EXTENSION_SBR &&
+            bits.object_type()? == AOT_SBR && bits.read(1)? != 0 {
+            config.sbr = true;
+            config.backward_compatible = true;
+            config.ext_sample_rate = bits.sample_rate()?;
+
+            if bits.remaining() >= 12 && bits.read(11)? == SYNC_EXTENSION_PS {
+                config.ps = bits.read(1)? != 0;
+            }
+        }
+
+        Ok((config, true))
+    }
+
+    // Only general audio object types with a channel configuration other
+    // than 0 (which needs a program config element) can be written.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, AscError> {
+        let mut bits = BitWriter::default();
+        self.write(&mut bits)?;
+        Ok(bits.finish())
+    }
+
+    pub(crate) fn write(&self, bits: &mut BitWriter) -> Result<(), AscError> {
+        if !is_general_audio(self.aot) || self.channel_config == 0 || self.channel_config > 15 ||
+            (self.ps && !self.sbr) || [6, 20, AOT_ER_BSAC].contains(&self.aot) {
+            return Err(AscError::Unsupported);
+        }
+
+        let hierarchical = self.sbr && !self.backward_compatible;
+
+        if hierarchical {
+            bits.object_type(if self.ps { AOT_PS } else { AOT_SBR });
+        } else {
+            bits.object_type(self.aot);
+        }
+        bits.sample_rate(self.sample_rate);
+        bits.write(self.channel_config as u32, 4);
+        if hierarchical {
+            bits.sample_rate(self.ext_sample_rate);
+            bits.object_type(self.aot);
+        }
+
+        // frameLengthFlag, dependsOnCoreCoder and extensionFlag
+        bits.write(self.frame_length_flag as u32, 1);
+        bits.write(0, 1);
+        let er = (17..=27).contains(&self.aot);
+        bits.write(er as u32, 1);
+        if er {
+            if [17, 19, 23].contains(&self.aot) {
+                bits.write(0, 3);
+            }
+            bits.write(0, 1);
+            // epConfig
+            bits.write(0, 2);
+        }
+
+        if self.sbr && self.backward_compatible {
+            bits.write(SYNC_EXTENSION_SBR, 11);
+            bits.object_type(AOT_SBR);
+            bits.write(1, 1);
+            bits.sample_rate(self.ext_sample_rate);
+            if self.ps {
+                bits.write(SYNC_EXTENSION_PS, 11);
+                bits.write(1, 1);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Per channel at the output, SBR included.
+    pub fn frame_size(&self) -> usize {
+        let core = match (self.aot, self.frame_length_flag) {
+            (23, false) | (AOT_ER_AAC_ELD, false) => 512,
+            (23, true) | (AOT_ER_AAC_ELD, true) => 480,
+            (_, false) => 1024,
+            (_, true) => 960,
+        };
+
+        if self.sbr && self.ext_sample_rate > self.sample_rate { core * 2 } else { core }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AscError {
+    Truncated,
+    Unsupported,
+}
+
+impl Display for AscError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AscError::Truncated => write!(f, "truncated AudioSpecificConfig"),
+            AscError::Unsupported => write!(f, "AudioSpecificConfig can't be written for this audio object type or channel configuration"),
+        }
+    }
+}
+
+impl std::error::Error for AscError {
+}
+
+pub fn sampling_frequency_index(sample_rate: u32) -> Option<u8> {
+    SAMPLING_FREQUENCIES.iter().position(|rate| *rate == sample_rate).map(|index| index as u8)
+}
+
+// AAC Main, LC, SSR, LTP, scalable, TwinVQ and their ER variants, all
+// using GASpecificConfig.
+fn is_general_audio(aot: u32) -> bool {
+    matches!(aot, 1..=4 | 6 | 7 | 17 | 19..=23)
+}
+
+#[derive(Clone)]
+pub(crate) struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        BitReader { data, pos: 0 }
+    }
+
+    pub(crate) fn read(&mut self, bits: usize) -> Result<u32, AscError> {
+        if bits > self.remaining() {
+            return Err(AscError::Truncated);
+        }
+
+        let mut value = 0;
+        for _ in 0..bits {
+            let bit = self.data[self.pos / 8] >> (7 - self.pos % 8) & 1;
+            value = value << 1 | bit as u32;
+            self.pos += 1;
+        }
+
+        Ok(value)
+    }
+
+    pub(crate) fn remaining(&self) -> usize {
+        self.data.len() * 8 - self.pos
+    }
+
+    pub(crate) fn position(&self) -> usize {
+        self.pos
+    }
+
+    // The next bits, realigned to start a byte.
+    pub(crate) fn bytes(&mut self, bits: usize) -> Result<Vec<u8>, AscError> {
+        if bits > self.remaining() {
+            return Err(AscError::Truncated);
+        }
+
+        let mut writer = BitWriter::default();
+        for _ in 0..bits / 8 {
+            writer.write(self.read(8)?, 8);
+        }
+        writer.write(self.read(bits % 8)?, bits % 8);
+
+        Ok(writer.finish())
+    }
+
+    fn object_type(&mut self) -> Result<u32, AscError> {
+        match self.read(5)? {
+            31 => Ok(32 + self.read(6)?),
+            aot => Ok(aot),
+        }
+    }
+
+    fn sample_rate(&mut self) -> Result<u32, AscError> {
+        match self.read(4)? {
+            15 => self.read(24),
+            index => SAMPLING_FREQUENCIES.get(index as usize).copied().ok_or(AscError::Unsupported),
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct BitWriter {
+    data: Vec<u8>,
+    bits: usize,
+}
+
+impl BitWriter {
+    pub(crate) fn write(&mut self, value: u32, bits: usize) {
+        for bit in (0..bits).rev() {
+            if self.bits == self.data.len() * 8 {
+                self.data.push(0);
+            }
+            let last = self.data.len() - 1;
+            self.data[last] |= ((value >> bit & 1) as u8) << (7 - self.bits % 8);
+            self.bits += 1;
+        }
+    }
+
+    pub(crate) fn write_bytes(&mut self, bytes: &[u8]) {
+        if self.bits == self.data.len() * 8 {
+            self.data.extend_from_slice(bytes);
+            self.bits += bytes.len() * 8;
+        } else {
+            for byte in bytes {
+                self.write(*byte as u32, 8);
+            }
+        }
+    }
+
+    // Padded with zero bits to a whole byte.
+    pub(crate) fn finish(self) -> Vec<u8> {
+        self.data
+    }
+
+    fn object_type(&mut self, aot: u32) {
+        if aot >= 32 {
+            self.write(31, 5);
+            self.write(aot - 32, 6);
+        } else {
+            self.write(aot, 5);
+        }
+    }
+
+    fn sample_rate(&mut self, sample_rate: u32) {
+        match sampling_frequency_index(sample_rate) {
+            Some(index) => self.write(index as u32, 4),
+            None => {
+                self.write(15, 4);
+                self.write(sample_rate, 24);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_round_trip() {
+        // AAC-LC, 44.1 kHz, stereo
+        let data = [0x12, 0x10];
+        let config = AudioSpecificConfig::parse(&data).unwrap();
+        assert_eq!(config, AudioSpecificConfig::new(44100, 2));
+        assert_eq!(config.frame_size(), 1024);
+        assert_eq!(config.to_bytes().unwrap(), data);
+    }
+
+    #[test]
+    fn hierarchical_sbr_round_trip() {
+        // SBR, 22.05 kHz core, stereo, 44.1 kHz output, AAC-LC
+        let data = [0x2b, 0x92, 0x08, 0x00];
+        let config = AudioSpecificConfig::parse(&data).unwrap();
+        assert_eq!(config, AudioSpecificConfig {
+            sbr: true,
+            ext_sample_rate: 44100,
+            ..AudioSpecificConfig::new(22050, 2)
+        });
+        assert_eq!(config.frame_size(), 2048);
+        assert_eq!(config.to_bytes().unwrap(), data);
+    }
+
+    #[test]
+    fn hierarchical_ps_round_trip() {
+        let config = AudioSpecificConfig {
+            sbr: true,
+            ps: true,
+            ext_sample_rate: 48000,
+            ..AudioSpecificConfig::new(24000, 1)
+        };
+        let data = config.to_bytes().unwrap();
+        assert_eq!(data[0] >> 3, AOT_PS as u8);
+        assert_eq!(AudioSpecificConfig::parse(&data).unwrap(), config);
+    }
+
+    #[test]
+    fn backward_compatible_round_trip() {
+        for ps in [false, true] {
+            let config = AudioSpecificConfig {
+                sbr: true,
+                ps,
+                ext_sample_rate: 44100,
+                backward_compatible: true,
+                ..AudioSpecificConfig::new(22050, if ps { 1 } else { 2 })
+            };
+            let data = config.to_bytes().unwrap();
+            // starts like plain AAC-LC for decoders not knowing about SBR
+            assert_eq!(data[0] >> 3, 2);
+            assert_eq!(AudioSpecificConfig::parse(&data).unwrap(), config);
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        assert_eq!(AudioSpecificConfig::parse(&[0x12]), Err(AscError::Truncated));
+    }
+
+    #[test]
+    fn unsupported() {
+        let config = AudioSpecificConfig { ps: true, ..AudioSpecificConfig::new(44100, 2) };
+        assert_eq!(config.to_bytes(), Err(AscError::Unsupported));
+        assert_eq!(AudioSpecificConfig::new(44100, 0).to_bytes(), Err(AscError::Unsupported));
+    }
+}
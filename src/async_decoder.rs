@@ -0,0 +1,109 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, Bytes};
+use futures_core::Stream;
+use tokio::sync::mpsc;
+
+use crate::dec::{self, Decoder, DecoderError, PcmFrame};
+
+// Decodes chunks of any size arriving on a channel. Frames are decoded
+// inline when the stream is polled, a frame takes well under a millisecond
+// so this does not need a blocking task.
+pub struct DecodedStream {
+    decoder: Decoder,
+    chunks: mpsc::Receiver<Bytes>,
+    pending: Bytes,
+    pcm: Vec<i16>,
+    closed: bool,
+    done: bool,
+}
+
+impl DecodedStream {
+    pub fn new(decoder: Decoder, chunks: mpsc::Receiver<Bytes>) -> Self {
+        DecodedStream {
+            decoder,
+            chunks,
+            pending: Bytes::new(),
+            pcm: vec![0; dec::MAX_OUTPUT_SAMPLES],
+            closed: false,
+            done: false,
+        }
+    }
+
+    pub fn decoder(&self) -> &Decoder {
+        &self.decoder
+    }
+
+    pub fn into_decoder(self) -> Decoder {
+        self.decoder
+    }
+
+    fn decode(&mut self) -> Result<Option<PcmFrame>, DecoderError> {
+        if !self.pending.is_empty() {
+            let consumed = self.decoder.feed(&self.pending)?;
+            self.pending.advance(consumed);
+        }
+
+        match self.decoder.decode_frame(&mut self.pcm) {
+            Ok(()) => {}
+            Err(e) if e == DecoderError::NOT_ENOUGH_BITS || e == DecoderError::TRANSPORT_SYNC_ERROR => return Ok(None),
+            // recoverable decode errors leave concealed PCM in the output
+            Err(e) if !e.is_recoverable() => return Err(e),
+            Err(_) => {}
+        }
+
+        let samples = self.decoder.decoded_frame_size();
+        Ok(Some(self.decoder.pcm_frame(&self.pcm[0..samples])))
+    }
+}
+
+impl Stream for DecodedStream {
+    type Item = Result<PcmFrame, DecoderError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        while !this.done {
+            match this.decode() {
+                Ok(Some(frame)) => return Poll::Ready(Some(Ok(frame))),
+                Ok(None) => {}
+                Err(e) => {
+                    this.done = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+
+            // input left over that did not fit the decoder buffer yet
+            if !this.pending.is_empty() {
+                continue;
+            }
+
+            if this.closed {
+                this.done = true;
+                if this.decoder.stats().frames_decoded == 0 {
+                    break;
+                }
+
+                let result = this.decoder.flush(&mut this.pcm)
+                    .map(|samples| this.decoder.pcm_frame(&this.pcm[0..samples]));
+                return Poll::Ready(Some(result));
+            }
+
+            match this.chunks.poll_recv(cx) {
+                Poll::Ready(Some(chunk)) => this.pending = chunk,
+                Poll::Ready(None) => this.closed = true,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        Poll::Ready(None)
+    }
+}
+
+// Send input chunks into the returned sender and drop it at the end of the
+// stream to get the delayed samples flushed.
+pub fn decode_stream(decoder: Decoder, buffer: usize) -> (mpsc::Sender<Bytes>, DecodedStream) {
+    let (sender, receiver) = mpsc::channel(buffer);
+    (sender, DecodedStream::new(decoder, receiver))
+}
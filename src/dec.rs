@@ -1,9 +1,53 @@
 use std::fmt::{self, Display, Debug};
+use std::io::{self, Read, Write};
 use std::os::raw::{c_uint, c_int};
+use std::ptr;
+use std::slice;
 
 use fdk_aac_sys as sys;
 
-pub use sys::CStreamInfo as StreamInfo;
+use crate::framing::{id3_tag_size, ID3_HEADER_SIZE};
+use crate::lib_info::{self, LibInfo};
+
+pub use crate::enc::ChannelOrder;
+
+// The AAC_DECODER_ERROR codes, DecoderError::kind() maps to these.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecoderErrorKind {
+    OutOfMemory,
+    Unknown,
+    TransportSyncError,
+    NotEnoughBits,
+    InvalidHandle,
+    UnsupportedAot,
+    UnsupportedFormat,
+    UnsupportedErFormat,
+    UnsupportedEpConfig,
+    UnsupportedMultilayer,
+    UnsupportedChannelConfig,
+    UnsupportedSamplingRate,
+    InvalidSbrConfig,
+    SetParamFail,
+    NeedToRestart,
+    OutputBufferTooSmall,
+    TransportError,
+    ParseError,
+    UnsupportedExtensionPayload,
+    DecodeFrameError,
+    CrcError,
+    InvalidCodeBook,
+    UnsupportedPrediction,
+    UnsupportedCce,
+    UnsupportedLfe,
+    UnsupportedGainControlData,
+    UnsupportedSba,
+    TnsReadError,
+    RvlcError,
+    AncDataError,
+    TooSmallAncBuffer,
+    TooManyAncElements,
+    Other(sys::AAC_DECODER_ERROR),
+}
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub struct DecoderError(sys::AAC_DECODER_ERROR);
@@ -42,6 +86,62 @@ impl DecoderError {
     pub const TOO_SMALL_ANC_BUFFER: DecoderError = DecoderError(sys::AAC_DECODER_ERROR_AAC_DEC_TOO_SMALL_ANC_BUFFER);
     pub const TOO_MANY_ANC_ELEMENTS: DecoderError = DecoderError(sys::AAC_DECODER_ERROR_AAC_DEC_TOO_MANY_ANC_ELEMENTS);
 
+    pub fn kind(&self) -> DecoderErrorKind {
+        match self.0 {
+            sys::AAC_DECODER_ERROR_AAC_DEC_OUT_OF_MEMORY => DecoderErrorKind::OutOfMemory,
+            sys::AAC_DECODER_ERROR_AAC_DEC_UNKNOWN => DecoderErrorKind::Unknown,
+            sys::AAC_DECODER_ERROR_AAC_DEC_TRANSPORT_SYNC_ERROR => DecoderErrorKind::TransportSyncError,
+            sys::AAC_DECODER_ERROR_AAC_DEC_NOT_ENOUGH_BITS => DecoderErrorKind::NotEnoughBits,
+            sys::AAC_DECODER_ERROR_AAC_DEC_INVALID_HANDLE => DecoderErrorKind::InvalidHandle,
+            sys::AAC_DECODER_ERROR_AAC_DEC_UNSUPPORTED_AOT => DecoderErrorKind::UnsupportedAot,
+            sys::AAC_DECODER_ERROR_AAC_DEC_UNSUPPORTED_FORMAT => DecoderErrorKind::UnsupportedFormat,
+            sys::AAC_DECODER_ERROR_AAC_DEC_UNSUPPORTED_ER_FORMAT => DecoderErrorKind::UnsupportedErFormat,
+            sys::AAC_DECODER_ERROR_AAC_DEC_UNSUPPORTED_EPCONFIG => DecoderErrorKind::UnsupportedEpConfig,
+            sys::AAC_DECODER_ERROR_AAC_DEC_UNSUPPORTED_MULTILAYER => DecoderErrorKind::UnsupportedMultilayer,
+            sys::AAC_DECODER_ERROR_AAC_DEC_UNSUPPORTED_CHANNELCONFIG => DecoderErrorKind::UnsupportedChannelConfig,
+            sys::AAC_DECODER_ERROR_AAC_DEC_UNSUPPORTED_SAMPLINGRATE => DecoderErrorKind::UnsupportedSamplingRate,
+            sys::AAC_DECODER_ERROR_AAC_DEC_INVALID_SBR_CONFIG => DecoderErrorKind::InvalidSbrConfig,
+            sys::AAC_DECODER_ERROR_AAC_DEC_SET_PARAM_FAIL => DecoderErrorKind::SetParamFail,
+            sys::AAC_DECODER_ERROR_AAC_DEC_NEED_TO_RESTART => DecoderErrorKind::NeedToRestart,
+            sys::AAC_DECODER_ERROR_AAC_DEC_OUTPUT_BUFFER_TOO_SMALL => DecoderErrorKind::OutputBufferTooSmall,
+            sys::AAC_DECODER_ERROR_AAC_DEC_TRANSPORT_ERROR => DecoderErrorKind::TransportError,
+            sys::AAC_DECODER_ERROR_AAC_DEC_PARSE_ERROR => DecoderErrorKind::ParseError,
+            sys::AAC_DECODER_ERROR_AAC_DEC_UNSUPPORTED_EXTENSION_PAYLOAD => DecoderErrorKind::UnsupportedExtensionPayload,
+            sys::AAC_DECODER_ERROR_AAC_DEC_DECODE_FRAME_ERROR => DecoderErrorKind::DecodeFrameError,
+            sys::AAC_DECODER_ERROR_AAC_DEC_CRC_ERROR => DecoderErrorKind::CrcError,
+            sys::AAC_DECODER_ERROR_AAC_DEC_INVALID_CODE_BOOK => DecoderErrorKind::InvalidCodeBook,
+            sys::AAC_DECODER_ERROR_AAC_DEC_UNSUPPORTED_PREDICTION => DecoderErrorKind::UnsupportedPrediction,
+            sys::AAC_DECODER_ERROR_AAC_DEC_UNSUPPORTED_CCE => DecoderErrorKind::UnsupportedCce,
+            sys::AAC_DECODER_ERROR_AAC_DEC_UNSUPPORTED_LFE => DecoderErrorKind::UnsupportedLfe,
+            sys::AAC_DECODER_ERROR_AAC_DEC_UNSUPPORTED_GAIN_CONTROL_DATA => DecoderErrorKind::UnsupportedGainControlData,
+            sys::AAC_DECODER_ERROR_AAC_DEC_UNSUPPORTED_SBA => DecoderErrorKind::UnsupportedSba,
+            sys::AAC_DECODER_ERROR_AAC_DEC_TNS_READ_ERROR => DecoderErrorKind::TnsReadError,
+            sys::AAC_DECODER_ERROR_AAC_DEC_RVLC_ERROR => DecoderErrorKind::RvlcError,
+            sys::AAC_DECODER_ERROR_AAC_DEC_ANC_DATA_ERROR => DecoderErrorKind::AncDataError,
+            sys::AAC_DECODER_ERROR_AAC_DEC_TOO_SMALL_ANC_BUFFER => DecoderErrorKind::TooSmallAncBuffer,
+            sys::AAC_DECODER_ERROR_AAC_DEC_TOO_MANY_ANC_ELEMENTS => DecoderErrorKind::TooManyAncElements,
+            code => DecoderErrorKind::Other(code),
+        }
+    }
+
+    // True when decoding can go on: more input is needed, sync was lost,
+    // the frame was concealed or only ancillary data was affected. Anything
+    // else means the decoder has to be reconfigured or recreated.
+    pub fn is_recoverable(&self) -> bool {
+        match *self {
+            DecoderError::NOT_ENOUGH_BITS |
+            DecoderError::TRANSPORT_SYNC_ERROR |
+            DecoderError::OUTPUT_BUFFER_TOO_SMALL => true,
+            _ => self.is_decode_error() ||
+                (sys::AAC_DECODER_ERROR_aac_dec_anc_data_error_start..=sys::AAC_DECODER_ERROR_aac_dec_anc_data_error_end).contains(&self.0),
+        }
+    }
+
+    // The output buffer holds concealed PCM for these.
+    fn is_decode_error(&self) -> bool {
+        (sys::AAC_DECODER_ERROR_aac_dec_decode_error_start..=sys::AAC_DECODER_ERROR_aac_dec_decode_error_end).contains(&self.0)
+    }
+
     pub fn message(&self) -> &'static str {
         match self.0 {
             sys::AAC_DECODER_ERROR_AAC_DEC_OK => "No error occurred. Output buffer is valid and error free.",
@@ -98,7 +198,35 @@ impl Display for DecoderError {
     }
 }
 
-fn check(e: sys::AACENC_ERROR) -> Result<(), DecoderError> {
+impl std::error::Error for DecoderError {
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    // the decoder was opened with layers, but configs were given
+    LayerCount { layers: usize, configs: usize },
+    Decoder(DecoderError),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::LayerCount { layers, configs } => write!(f, "{} configs given for a decoder with {} layers", configs, layers),
+            ConfigError::Decoder(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+}
+
+impl From<DecoderError> for ConfigError {
+    fn from(e: DecoderError) -> Self {
+        ConfigError::Decoder(e)
+    }
+}
+
+fn check(e: sys::AAC_DECODER_ERROR) -> Result<(), DecoderError> {
     if e == sys::AAC_DECODER_ERROR_AAC_DEC_OK {
         Ok(())
     } else {
@@ -106,53 +234,260 @@ fn check(e: sys::AACENC_ERROR) -> Result<(), DecoderError> {
     }
 }
 
-#[derive(Debug)]
+// Capabilities are the sys::CAPF_* flags of the FDK_AACDEC and FDK_SBRDEC
+// entries, e.g. sys::CAPF_SBR_HQ (HE-AAC), sys::CAPF_ER_AAC_ELD or sys::CAPF_AAC_USAC.
+pub fn lib_info() -> Result<Vec<LibInfo>, DecoderError> {
+    lib_info::collect(|table| {
+        if unsafe { sys::aacDecoder_GetLibInfo(table) } == 0 {
+            Ok(())
+        } else {
+            Err(DecoderError::UNKNOWN)
+        }
+    })
+}
+
+// xHE-AAC streams fail with UNSUPPORTED_AOT when the linked libfdk-aac is
+// older than 2.0 or was built without USAC, check this first to tell the
+// two apart.
+pub fn usac_supported() -> bool {
+    let infos = match lib_info() {
+        Ok(infos) => infos,
+        Err(_) => return false,
+    };
+
+    lib_info::find(&infos, sys::FDK_MODULE_ID_FDK_AACDEC)
+        .map(|info| info.has_capability(sys::CAPF_AAC_USAC))
+        .unwrap_or(false)
+}
+
+struct DecoderHandle {
+    ptr: sys::HANDLE_AACDECODER,
+}
+
+impl DecoderHandle {
+    pub fn alloc(transport: sys::TRANSPORT_TYPE, layers: usize) -> Result<Self, DecoderError> {
+        let ptr = unsafe { sys::aacDecoder_Open(transport, layers as c_uint) };
+        if ptr.is_null() {
+            return Err(DecoderError::INVALID_HANDLE);
+        }
+        Ok(DecoderHandle { ptr })
+    }
+}
+
+impl Drop for DecoderHandle {
+    fn drop(&mut self) {
+        unsafe { sys::aacDecoder_Close(self.ptr); }
+    }
+}
+
 pub struct Decoder {
-    handle: sys::HANDLE_AACDECODER,
+    handle: DecoderHandle,
+    transport: Transport,
+    layers: usize,
+    // applied to the next DecodeFrame call
+    pending_flags: c_uint,
+    // registered with the library, which writes the elements of each
+    // decoded frame into it. Declared after handle so it outlives it.
+    ancillary: Vec<u8>,
+    // of the last frame returned by try_decode_frame
+    format: Option<OutputFormat>,
+    stats: DecoderStats,
+    scratch: Vec<i16>,
 }
 
 impl Decoder {
-    pub fn new(transport: Transport) -> Self {
-        let handle = match transport {
-            Transport::Adts => {
-                unsafe { sys::aacDecoder_Open(sys::TRANSPORT_TYPE_TT_MP4_ADTS, 1) }
-            }
-        };
+    pub fn new(transport: Transport) -> Result<Self, DecoderError> {
+        Decoder::with_layers(transport, 1)
+    }
+
+    // libfdk-aac only takes the number of layers when opening the decoder,
+    // it has no program count or selection. In multi-program LATM/LOAS
+    // streams the first program is decoded.
+    pub fn with_layers(transport: Transport, layers: usize) -> Result<Self, DecoderError> {
+        let handle = DecoderHandle::alloc(transport.transport_type(), layers)?;
 
-        Decoder { handle }
+        Ok(Decoder {
+            handle,
+            transport,
+            layers,
+            pending_flags: 0,
+            ancillary: Vec::new(),
+            format: None,
+            stats: DecoderStats::default(),
+            scratch: Vec::new(),
+        })
     }
 
-    pub fn config_raw(&mut self, audio_specic_config: &[u8]) -> Result<(), DecoderError> {
-        unsafe {
-            let mut asc_ptr = audio_specic_config.as_ptr() as *mut u8;
-            let asc_len = audio_specic_config.len() as c_uint;
-            check(sys::aacDecoder_ConfigRaw(self.handle, &mut asc_ptr as *mut _, &asc_len as *const _))
+    pub fn builder() -> DecoderBuilder {
+        DecoderBuilder {
+            transport: Transport::Adts,
+            layers: 1,
+            audio_specific_configs: None,
+            conceal_method: None,
+            limiter: None,
+            drc: None,
+            drc_effect: None,
+            channel_order: None,
+            output_channels: None,
+            dual_channel_mode: None,
         }
     }
 
-    pub fn set_min_output_channels(&mut self, channels: usize) -> Result<(), DecoderError> {
-        unsafe {
-            check(sys::aacDecoder_SetParam(self.handle,
-                sys::AACDEC_PARAM_AAC_PCM_MIN_OUTPUT_CHANNELS,
-                channels as i32))
+    // For access units demuxed from MP4/MKV, with the AudioSpecificConfig
+    // from the esds box or CodecPrivate.
+    pub fn new_raw(audio_specific_config: &[u8]) -> Result<Self, DecoderError> {
+        let mut decoder = Decoder::new(Transport::Raw)?;
+        // opened with a single layer
+        decoder.configure(&[audio_specific_config])?;
+        Ok(decoder)
+    }
+
+    pub fn config_raw(&mut self, audio_specific_config: &[u8]) -> Result<(), ConfigError> {
+        self.config_raw_layers(&[audio_specific_config])
+    }
+
+    // One config per layer the decoder was opened with.
+    pub fn config_raw_layers(&mut self, configs: &[&[u8]]) -> Result<(), ConfigError> {
+        // the library reads a config for every layer
+        if configs.len() != self.layers {
+            return Err(ConfigError::LayerCount { layers: self.layers, configs: configs.len() });
         }
+
+        Ok(self.configure(configs)?)
+    }
+
+    fn configure(&mut self, configs: &[&[u8]]) -> Result<(), DecoderError> {
+        let mut asc_ptrs: Vec<*mut u8> = configs.iter().map(|config| config.as_ptr() as *mut u8).collect();
+        let asc_lens: Vec<c_uint> = configs.iter().map(|config| config.len() as c_uint).collect();
+
+        unsafe { check(sys::aacDecoder_ConfigRaw(self.handle.ptr, asc_ptrs.as_mut_ptr(), asc_lens.as_ptr())) }
+    }
+
+    fn set_param(&mut self, param: sys::AACDEC_PARAM, value: i32) -> Result<(), DecoderError> {
+        unsafe { check(sys::aacDecoder_SetParam(self.handle.ptr, param, value)) }
+    }
+
+    pub fn set_min_output_channels(&mut self, channels: usize) -> Result<(), DecoderError> {
+        self.set_param(sys::AACDEC_PARAM_AAC_PCM_MIN_OUTPUT_CHANNELS, channels as i32)
     }
 
     pub fn set_max_output_channels(&mut self, channels: usize) -> Result<(), DecoderError> {
-        unsafe {
-            check(sys::aacDecoder_SetParam(self.handle,
-                sys::AACDEC_PARAM_AAC_PCM_MAX_OUTPUT_CHANNELS,
-                channels as i32))
-        }
+        self.set_param(sys::AACDEC_PARAM_AAC_PCM_MAX_OUTPUT_CHANNELS, channels as i32)
     }
 
-    pub fn fill(&mut self, data: &[u8]) -> Result<usize, DecoderError> {
+    // Downmixes or upmixes to exactly this many channels, e.g. 2 to get
+    // stereo from 5.1 broadcasts as well as from mono content.
+    pub fn set_output_channels(&mut self, channels: usize) -> Result<(), DecoderError> {
+        self.set_max_output_channels(channels)?;
+        self.set_min_output_channels(channels)
+    }
+
+    // Defaults to WAV order.
+    pub fn set_channel_order(&mut self, order: ChannelOrder) -> Result<(), DecoderError> {
+        let mapping = match order {
+            ChannelOrder::Mpeg => 0,
+            ChannelOrder::Wav => 1,
+        };
+        self.set_param(sys::AACDEC_PARAM_AAC_PCM_OUTPUT_CHANNEL_MAPPING, mapping)
+    }
+
+    pub fn set_dual_channel_mode(&mut self, mode: DualChannelMode) -> Result<(), DecoderError> {
+        self.set_param(sys::AACDEC_PARAM_AAC_PCM_DUAL_CHANNEL_OUTPUT_MODE, mode.mode())
+    }
+
+    pub fn set_conceal_method(&mut self, method: ConcealMethod) -> Result<(), DecoderError> {
+        self.set_param(sys::AACDEC_PARAM_AAC_CONCEAL_METHOD, method.method())
+    }
+
+    // None restores the default, where the limiter is only enabled when
+    // needed. It adds a frame of delay, which matters for low latency use.
+    pub fn set_limiter_enabled(&mut self, enabled: Option<bool>) -> Result<(), DecoderError> {
+        self.set_param(sys::AACDEC_PARAM_AAC_PCM_LIMITER_ENABLE, enabled.map_or(-1, |enabled| enabled as i32))
+    }
+
+    // 1 to 15 ms, defaults to 15 ms.
+    pub fn set_limiter_attack_time(&mut self, ms: u32) -> Result<(), DecoderError> {
+        self.set_param(sys::AACDEC_PARAM_AAC_PCM_LIMITER_ATTACK_TIME, ms as i32)
+    }
+
+    // Defaults to 50 ms.
+    pub fn set_limiter_release_time(&mut self, ms: u32) -> Result<(), DecoderError> {
+        self.set_param(sys::AACDEC_PARAM_AAC_PCM_LIMITER_RELEAS_TIME, ms as i32)
+    }
+
+    // 0.0 ignores the boost factors in the bitstream, 1.0 (the default)
+    // applies them fully.
+    pub fn set_drc_boost(&mut self, factor: f32) -> Result<(), DecoderError> {
+        self.set_param(sys::AACDEC_PARAM_AAC_DRC_BOOST_FACTOR, (factor * 127.0).round() as i32)
+    }
+
+    // Same as set_drc_boost for the attenuation factors.
+    pub fn set_drc_attenuation(&mut self, factor: f32) -> Result<(), DecoderError> {
+        self.set_param(sys::AACDEC_PARAM_AAC_DRC_ATTENUATION_FACTOR, (factor * 127.0).round() as i32)
+    }
+
+    // Target loudness in dBFS, -10.0 to -31.75, None disables loudness
+    // normalization. Also the target for MPEG-D DRC in xHE-AAC streams.
+    pub fn set_drc_reference_level(&mut self, level: Option<f32>) -> Result<(), DecoderError> {
+        // passed as steps of 0.25 dB below full scale
+        let level = level.map(|db| (-db * 4.0).round() as i32).unwrap_or(-1);
+        self.set_param(sys::AACDEC_PARAM_AAC_DRC_REFERENCE_LEVEL, level)
+    }
+
+    // Night mode, uses the heavy compression gains of the bitstream.
+    pub fn set_drc_heavy_compression(&mut self, enabled: bool) -> Result<(), DecoderError> {
+        self.set_param(sys::AACDEC_PARAM_AAC_DRC_HEAVY_COMPRESSION, enabled as i32)
+    }
+
+    pub fn set_drc_effect(&mut self, effect: DrcEffect) -> Result<(), DecoderError> {
+        self.set_param(sys::AACDEC_PARAM_AAC_UNIDRC_SET_EFFECT, effect.effect())
+    }
+
+    // Uses the album loudness rather than the track loudness for
+    // normalization, so levels within an album stay as mastered.
+    pub fn set_drc_album_mode(&mut self, enabled: bool) -> Result<(), DecoderError> {
+        self.set_param(sys::AACDEC_PARAM_AAC_UNIDRC_ALBUM_MODE, enabled as i32)
+    }
+
+    pub fn set_metadata_profile(&mut self, profile: MetadataProfile) -> Result<(), DecoderError> {
+        self.set_param(sys::AACDEC_PARAM_AAC_METADATA_PROFILE, profile.profile() as i32)
+    }
+
+    // libfdk-aac has no separate switch for the PCE matrix mixdown, it is
+    // part of the metadata profile. Enabling selects MpegLegacyPrio, which
+    // applies the PCE coefficient whenever present, disabling selects the
+    // default MpegStandard, which ignores it.
+    pub fn set_matrix_mixdown(&mut self, enabled: bool) -> Result<(), DecoderError> {
+        self.set_metadata_profile(if enabled {
+            MetadataProfile::MpegLegacyPrio
+        } else {
+            MetadataProfile::MpegStandard
+        })
+    }
+
+    // How long DRC and downmix metadata from the bitstream stays in effect
+    // after it stops arriving, None keeps it indefinitely.
+    pub fn set_metadata_expiry_time(&mut self, ms: Option<u32>) -> Result<(), DecoderError> {
+        self.set_param(sys::AACDEC_PARAM_AAC_METADATA_EXPIRY_TIME, ms.map(|ms| ms as i32).unwrap_or(-1))
+    }
+
+    // Real valued (low power) QMF processing for SBR, cheaper on CPU at a
+    // small cost in quality. None restores the default, where the library
+    // picks the mode.
+    pub fn set_qmf_low_power(&mut self, enabled: Option<bool>) -> Result<(), DecoderError> {
+        self.set_param(sys::AACDEC_PARAM_AAC_QMF_LOWPOWER, enabled.map_or(-1, |enabled| enabled as i32))
+    }
+
+    // Copies as much of data into the internal input buffer as fits and
+    // returns the number of bytes consumed. The rest has to be fed again
+    // after decoding, free_input_bytes() tells how much will be accepted.
+    pub fn feed(&mut self, data: &[u8]) -> Result<usize, DecoderError> {
         unsafe {
-            let mut data_ptr = data.as_ptr() as *const u8 as *mut u8;
+            let mut data_ptr = data.as_ptr() as *mut u8;
             let data_len = data.len() as c_uint;
             let mut bytes_valid: c_uint = data_len;
 
-            check(sys::aacDecoder_Fill(self.handle,
+            check(sys::aacDecoder_Fill(self.handle.ptr,
                 &mut data_ptr as *mut _,
                 &data_len as *const _,
                 &mut bytes_valid as *mut _))?;
@@ -161,36 +496,1187 @@ impl Decoder {
         }
     }
 
+    #[deprecated(note = "renamed to feed")]
+    pub fn fill(&mut self, data: &[u8]) -> Result<usize, DecoderError> {
+        self.feed(data)
+    }
+
+    pub fn free_input_bytes(&self) -> Result<usize, DecoderError> {
+        let mut free_bytes: c_uint = 0;
+        unsafe { check(sys::aacDecoder_GetFreeBytes(self.handle.ptr, &mut free_bytes))?; }
+        Ok(free_bytes as usize)
+    }
+
     pub fn decode_frame(&mut self, pcm: &mut [i16]) -> Result<(), DecoderError> {
-        unsafe {
-            check(sys::aacDecoder_DecodeFrame(self.handle,
-                pcm.as_mut_ptr() as *mut i16,
+        self.decode_with_flags(pcm, 0)
+    }
+
+    // Decodes straight into out without an intermediate buffer, only the
+    // first info.samples entries are written.
+    pub fn decode_frame_into(&mut self, out: &mut [i16]) -> Result<DecodeInfo, DecoderError> {
+        self.decode_frame(out)?;
+
+        let info = self.raw_stream_info();
+        Ok(DecodeInfo {
+            samples: info.numChannels as usize * info.frameSize as usize,
+            frame_size: info.frameSize as usize,
+            channels: info.numChannels as usize,
+            sample_rate: info.sampleRate as u32,
+        })
+    }
+
+    // Deinterleaves into one buffer per output channel, each of which has to
+    // hold a full frame.
+    pub fn decode_frame_planar(&mut self, channels: &mut [&mut [i16]]) -> Result<DecodeInfo, DecoderError> {
+        // checked against the current format before decoding, so a frame is
+        // only lost to short buffers on the first frame or a format change
+        let fits = |channels: &[&mut [i16]], count: usize, frame_size: usize| {
+            channels.len() >= count && channels.iter().all(|channel| channel.len() >= frame_size)
+        };
+
+        let current = self.raw_stream_info();
+        if !fits(channels, current.numChannels as usize, current.frameSize as usize) {
+            return Err(DecoderError::OUTPUT_BUFFER_TOO_SMALL);
+        }
+
+        let mut scratch = std::mem::take(&mut self.scratch);
+        scratch.resize(MAX_OUTPUT_SAMPLES, 0);
+
+        let result = self.decode_frame_into(&mut scratch);
+        self.scratch = scratch;
+        let info = result?;
+
+        if !fits(channels, info.channels, info.frame_size) {
+            return Err(DecoderError::OUTPUT_BUFFER_TOO_SMALL);
+        }
+
+        for (index, frame) in self.scratch[..info.samples].chunks_exact(info.channels).enumerate() {
+            for (channel, sample) in channels.iter_mut().zip(frame) {
+                channel[index] = *sample;
+            }
+        }
+
+        Ok(info)
+    }
+
+    // Samples are normalized to -1.0..1.0.
+    pub fn decode_frame_f32(&mut self, pcm: &mut [f32]) -> Result<(), DecoderError> {
+        let mut scratch = std::mem::take(&mut self.scratch);
+        scratch.resize(pcm.len(), 0);
+
+        let result = self.decode_frame(&mut scratch);
+        if result.is_ok() {
+            let len = self.decoded_frame_size().min(pcm.len());
+            for (out, sample) in pcm[..len].iter_mut().zip(&scratch) {
+                *out = *sample as f32 / 32768.0;
+            }
+        }
+
+        self.scratch = scratch;
+        result
+    }
+
+    // For packet oriented input with exactly one ADTS frame per packet. A
+    // truncated packet fails with NOT_ENOUGH_BITS and is dropped, so the next
+    // one starts clean.
+    pub fn decode_adts_frame(&mut self, frame: &[u8]) -> Result<PcmFrame, DecoderError> {
+        if self.transport != Transport::Adts {
+            return Err(DecoderError::UNSUPPORTED_FORMAT);
+        }
+
+        if self.feed(frame)? < frame.len() {
+            // far larger than any ADTS frame
+            self.clear_transport_buffer()?;
+            return Err(DecoderError::TRANSPORT_ERROR);
+        }
+
+        let mut scratch = std::mem::take(&mut self.scratch);
+        scratch.resize(MAX_OUTPUT_SAMPLES, 0);
+
+        let result = self.decode_frame(&mut scratch);
+        if result == Err(DecoderError::NOT_ENOUGH_BITS) {
+            self.clear_transport_buffer()?;
+        }
+        let frame = result.map(|()| self.pcm_frame(&scratch[..self.decoded_frame_size()]));
+
+        self.scratch = scratch;
+        frame
+    }
+
+    // Synthesizes a frame in place of one that never arrived, using the
+    // configured conceal method. Returns the number of samples written.
+    pub fn decode_lost_frame(&mut self, pcm: &mut [i16]) -> Result<usize, DecoderError> {
+        self.decode_with_flags(pcm, sys::AACDEC_CONCEAL)?;
+        Ok(self.decoded_frame_size())
+    }
+
+    // Call at the end of the stream to get the samples held back by the
+    // decoder delay, stream_info().output_delay per channel, at the start of
+    // one more frame. Returns the number of samples written.
+    pub fn flush(&mut self, pcm: &mut [i16]) -> Result<usize, DecoderError> {
+        self.decode_with_flags(pcm, sys::AACDEC_FLUSH)?;
+
+        // the rest of the frame is not part of the stream
+        let info = self.raw_stream_info();
+        Ok((info.outputDelay as usize * info.numChannels as usize).min(self.decoded_frame_size()))
+    }
+
+    // Drops all input fed so far that has not been decoded yet.
+    pub fn clear_transport_buffer(&mut self) -> Result<(), DecoderError> {
+        self.set_param(sys::AACDEC_PARAM_AAC_TPDEC_CLEAR_BUFFER, 1)
+    }
+
+    // Call after seeking: drops buffered input and makes the next decoded
+    // frame resynchronize instead of overlapping with stale state.
+    pub fn signal_discontinuity(&mut self) -> Result<(), DecoderError> {
+        self.clear_transport_buffer()?;
+        self.pending_flags |= sys::AACDEC_INTR;
+        Ok(())
+    }
+
+    // For sample accurate seeking in streams with fixed size frames. Resets
+    // the decoder like signal_discontinuity and returns where to resume
+    // feeding input and how much of the output to drop. None until the
+    // first frame has been decoded and the frame size is known.
+    pub fn prepare_seek(&mut self, target: u64) -> Result<Option<SeekPoint>, DecoderError> {
+        let info = self.stream_info();
+        let seek_point = match SeekPoint::new(target, info.frame_size, info.output_delay) {
+            Some(seek_point) => seek_point,
+            None => return Ok(None),
+        };
+
+        self.signal_discontinuity()?;
+        Ok(Some(seek_point))
+    }
+
+    fn decode_with_flags(&mut self, pcm: &mut [i16], flags: c_uint) -> Result<(), DecoderError> {
+        let flags = flags | self.pending_flags;
+
+        let result = unsafe {
+            check(sys::aacDecoder_DecodeFrame(self.handle.ptr,
+                pcm.as_mut_ptr(),
                 pcm.len() as c_int,
-                0))
+                flags))
+        };
+
+        // keep the flags around until a frame was actually decoded
+        if result != Err(DecoderError::NOT_ENOUGH_BITS) {
+            self.pending_flags = 0;
+        }
+
+        match result {
+            // the flush output isn't from an access unit of the input
+            Ok(()) if flags & sys::AACDEC_FLUSH != 0 => {}
+            Ok(()) => {
+                self.stats.frames_decoded += 1;
+                if flags & sys::AACDEC_CONCEAL != 0 {
+                    self.stats.frames_concealed += 1;
+                }
+            }
+            Err(DecoderError::NOT_ENOUGH_BITS) => {}
+            Err(e) => {
+                self.stats.errors += 1;
+                if e.is_decode_error() {
+                    self.stats.frames_concealed += 1;
+                }
+            }
+        }
+
+        result
+    }
+
+    // Has to be called before decoding for ancillary_data() to return
+    // anything, capacity is the total size of all elements of a frame.
+    pub fn enable_ancillary_data(&mut self, capacity: usize) -> Result<(), DecoderError> {
+        self.ancillary = vec![0; capacity];
+        unsafe {
+            check(sys::aacDecoder_AncDataInit(self.handle.ptr,
+                self.ancillary.as_mut_ptr(),
+                capacity as c_int))
+        }
+    }
+
+    // The ancillary data elements (e.g. DAB+ PAD) of the last decoded frame.
+    pub fn ancillary_data(&self) -> Vec<&[u8]> {
+        let mut elements = Vec::new();
+        if self.ancillary.is_empty() {
+            return elements;
+        }
+
+        for index in 0.. {
+            let mut ptr: *mut u8 = ptr::null_mut();
+            let mut size: c_int = 0;
+
+            let result = unsafe {
+                check(sys::aacDecoder_AncDataGet(self.handle.ptr, index, &mut ptr, &mut size))
+            };
+            if result.is_err() || ptr.is_null() || size <= 0 {
+                break;
+            }
+
+            elements.push(unsafe { slice::from_raw_parts(ptr as *const u8, size as usize) });
+        }
+
+        elements
+    }
+
+    // For streams arriving in arbitrary chunks. The first frame always
+    // reports OutputFormatChanged, later ones only when the sample rate or
+    // channel count changed mid-stream.
+    pub fn try_decode_frame(&mut self, pcm: &mut [i16]) -> Result<DecodeStatus, DecoderError> {
+        match self.decode_frame(pcm) {
+            Ok(()) => {}
+            Err(DecoderError::NOT_ENOUGH_BITS) => return Ok(DecodeStatus::NeedMoreData),
+            Err(e) => return Err(e),
+        }
+
+        let info = self.raw_stream_info();
+        let format = OutputFormat {
+            sample_rate: info.sampleRate as u32,
+            channels: info.numChannels as usize,
+        };
+
+        if self.format.replace(format) == Some(format) {
+            Ok(DecodeStatus::Frame)
+        } else {
+            Ok(DecodeStatus::OutputFormatChanged(format))
         }
     }
 
     pub fn decoded_frame_size(&self) -> usize {
-        let stream_info = self.stream_info();
+        let stream_info = self.raw_stream_info();
 
         stream_info.numChannels as usize * stream_info.frameSize as usize
     }
 
-    pub fn stream_info(&self) -> &StreamInfo {
-        unsafe { &*sys::aacDecoder_GetStreamInfo(self.handle) }
+    // Refreshed by every decoded frame, all zero before the first one.
+    pub fn stream_info(&self) -> StreamInfo {
+        let info = self.raw_stream_info();
+
+        StreamInfo {
+            sample_rate: info.sampleRate as u32,
+            channels: info.numChannels as usize,
+            frame_size: info.frameSize as usize,
+            aot: info.aot,
+            ext_aot: info.extAot,
+            bit_rate: info.bitRate as u32,
+            channel_config: info.channelConfig as u32,
+            aac_sample_rate: info.aacSampleRate as u32,
+            ext_sample_rate: info.extSamplingRate as u32,
+            aac_samples_per_frame: info.aacSamplesPerFrame as usize,
+            output_delay: info.outputDelay as usize,
+            ep_config: info.epConfig as i32,
+        }
     }
+
+    // One entry per output channel, in output order. Empty until the first
+    // frame has been decoded.
+    pub fn channel_layout(&self) -> Vec<ChannelPosition> {
+        let info = self.raw_stream_info();
+        if info.pChannelType.is_null() || info.pChannelIndices.is_null() || info.numChannels <= 0 {
+            return Vec::new();
+        }
+
+        let channels = info.numChannels as usize;
+        let types = unsafe { slice::from_raw_parts(info.pChannelType, channels) };
+        let indices = unsafe { slice::from_raw_parts(info.pChannelIndices, channels) };
+
+        types.iter().zip(indices)
+            .map(|(channel_type, index)| ChannelPosition {
+                channel_type: ChannelType::from_raw(*channel_type),
+                index: *index,
+            })
+            .collect()
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    pub fn stats(&self) -> DecoderStats {
+        let info = self.raw_stream_info();
+
+        DecoderStats {
+            total_bytes: info.numTotalBytes as u64,
+            bad_bytes: info.numBadBytes as u64,
+            total_access_units: info.numTotalAccessUnits as u64,
+            bad_access_units: info.numBadAccessUnits as u64,
+            ..self.stats
+        }
+    }
+
+    // Decodes everything read from input, across format changes. Decoder
+    // errors are reported as io::ErrorKind::InvalidData.
+    pub fn frames<R: Read>(&mut self, input: R) -> Frames<'_, R> {
+        Frames {
+            decoder: self,
+            input,
+            input_buffer: Vec::new(),
+            input_pos: 0,
+            input_len: 0,
+            pcm: Vec::new(),
+            keep_id3_tag: false,
+            id3_tag: None,
+            resync: false,
+            searching: false,
+            skipped_bytes: 0,
+            trim_start: 0,
+            trim_end: 0,
+            held: Vec::new(),
+            eof: false,
+            done: false,
+        }
+    }
+
+    // Writes interleaved native endian 16 bit PCM, the counterpart of
+    // Encoder::encode.
+    pub fn decode<R: Read, W: Write>(&mut self, input: &mut R, output: &mut W) -> io::Result<DecodeTotals> {
+        let mut totals = DecodeTotals { frames: 0, samples: 0, output_size: 0 };
+        let mut bytes = Vec::new();
+
+        for frame in self.frames(input) {
+            let frame = frame?;
+
+            bytes.clear();
+            bytes.extend(frame.samples.iter().flat_map(|sample| sample.to_ne_bytes()));
+            output.write_all(&bytes)?;
+
+            totals.frames += 1;
+            totals.samples += frame.samples.len();
+            totals.output_size += bytes.len();
+        }
+
+        Ok(totals)
+    }
+
+    // Copies decoded samples into a PcmFrame tagged with the current format.
+    pub(crate) fn pcm_frame(&self, samples: &[i16]) -> PcmFrame {
+        let info = self.raw_stream_info();
+
+        PcmFrame {
+            samples: samples.to_vec(),
+            channels: info.numChannels as usize,
+            sample_rate: info.sampleRate as u32,
+        }
+    }
+
+    fn raw_stream_info(&self) -> &sys::CStreamInfo {
+        unsafe { &*sys::aacDecoder_GetStreamInfo(self.handle.ptr) }
+    }
+}
+
+// Enough for 8 channels of the largest (USAC) frames.
+pub(crate) const MAX_OUTPUT_SAMPLES: usize = 4096 * 8;
+
+const INPUT_CHUNK_SIZE: usize = 4096;
+
+// Factors are 0.0 to 1.0, the reference level is in dBFS, see the
+// Decoder::set_drc_* setters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Drc {
+    pub boost: f32,
+    pub attenuation: f32,
+    pub reference_level: Option<f32>,
+    pub heavy_compression: bool,
+}
+
+impl Default for Drc {
+    fn default() -> Self {
+        Drc {
+            boost: 1.0,
+            attenuation: 1.0,
+            reference_level: None,
+            heavy_compression: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DecoderBuilder {
+    transport: Transport,
+    layers: usize,
+    audio_specific_configs: Option<Vec<Vec<u8>>>,
+    conceal_method: Option<ConcealMethod>,
+    limiter: Option<bool>,
+    drc: Option<Drc>,
+    drc_effect: Option<DrcEffect>,
+    channel_order: Option<ChannelOrder>,
+    output_channels: Option<usize>,
+    dual_channel_mode: Option<DualChannelMode>,
+}
+
+#[derive(Debug)]
+pub enum DecoderBuilderError {
+    // the named builder setting is out of range or inconsistent
+    InvalidParams(&'static str),
+    Decoder(DecoderError),
 }
 
+impl Display for DecoderBuilderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecoderBuilderError::InvalidParams(msg) => write!(f, "{}", msg),
+            DecoderBuilderError::Decoder(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for DecoderBuilderError {
+}
+
+impl From<DecoderError> for DecoderBuilderError {
+    fn from(e: DecoderError) -> Self {
+        DecoderBuilderError::Decoder(e)
+    }
+}
+
+impl From<ConfigError> for DecoderBuilderError {
+    fn from(e: ConfigError) -> Self {
+        match e {
+            ConfigError::LayerCount { .. } => DecoderBuilderError::InvalidParams("audio_specific_config needs one config per layer"),
+            ConfigError::Decoder(e) => DecoderBuilderError::Decoder(e),
+        }
+    }
+}
+
+impl DecoderBuilder {
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    pub fn layers(mut self, layers: usize) -> Self {
+        self.layers = layers;
+        self
+    }
+
+    // Required for Transport::Raw, Drm and LatmMcp0, passed to config_raw.
+    pub fn audio_specific_config(mut self, audio_specific_config: &[u8]) -> Self {
+        self.audio_specific_configs = Some(vec![audio_specific_config.to_vec()]);
+        self
+    }
+
+    // One config per layer, passed to config_raw_layers.
+    pub fn audio_specific_config_layers(mut self, configs: &[&[u8]]) -> Self {
+        self.audio_specific_configs = Some(configs.iter().map(|config| config.to_vec()).collect());
+        self
+    }
+
+    pub fn conceal(mut self, conceal_method: ConcealMethod) -> Self {
+        self.conceal_method = Some(conceal_method);
+        self
+    }
+
+    pub fn limiter(mut self, enabled: bool) -> Self {
+        self.limiter = Some(enabled);
+        self
+    }
+
+    pub fn drc(mut self, drc: Drc) -> Self {
+        self.drc = Some(drc);
+        self
+    }
+
+    pub fn drc_effect(mut self, drc_effect: DrcEffect) -> Self {
+        self.drc_effect = Some(drc_effect);
+        self
+    }
+
+    pub fn channel_order(mut self, channel_order: ChannelOrder) -> Self {
+        self.channel_order = Some(channel_order);
+        self
+    }
+
+    pub fn output_channels(mut self, output_channels: usize) -> Self {
+        self.output_channels = Some(output_channels);
+        self
+    }
+
+    pub fn dual_channel_mode(mut self, dual_channel_mode: DualChannelMode) -> Self {
+        self.dual_channel_mode = Some(dual_channel_mode);
+        self
+    }
+
+    fn validate(&self) -> Result<(), DecoderBuilderError> {
+        if self.layers == 0 {
+            return Err(DecoderBuilderError::InvalidParams("layers must be at least 1"));
+        }
+
+        if self.transport.needs_config() != self.audio_specific_configs.is_some() {
+            return Err(DecoderBuilderError::InvalidParams(if self.transport.needs_config() {
+                "audio_specific_config is required for this transport"
+            } else {
+                "audio_specific_config is only used with Transport::Raw, Drm and LatmMcp0"
+            }));
+        }
+
+        if let Some(configs) = &self.audio_specific_configs {
+            if configs.len() != self.layers {
+                return Err(DecoderBuilderError::InvalidParams("audio_specific_config needs one config per layer"));
+            }
+        }
+
+        if let Some(drc) = &self.drc {
+            let factors = 0.0..=1.0;
+            if !factors.contains(&drc.boost) {
+                return Err(DecoderBuilderError::InvalidParams("drc boost must be between 0.0 and 1.0"));
+            }
+
+            if !factors.contains(&drc.attenuation) {
+                return Err(DecoderBuilderError::InvalidParams("drc attenuation must be between 0.0 and 1.0"));
+            }
+
+            if let Some(level) = drc.reference_level {
+                if !(-31.75..=-10.0).contains(&level) {
+                    return Err(DecoderBuilderError::InvalidParams("drc reference_level must be between -31.75 and -10.0 dB"));
+                }
+            }
+        }
+
+        if let Some(channels) = self.output_channels {
+            match channels {
+                1 | 2 | 6 | 8 => {}
+                _ => return Err(DecoderBuilderError::InvalidParams("output_channels must be 1, 2, 6 or 8")),
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn build(self) -> Result<Decoder, DecoderBuilderError> {
+        self.validate()?;
+
+        let mut decoder = Decoder::with_layers(self.transport, self.layers)?;
+
+        if let Some(configs) = &self.audio_specific_configs {
+            let configs: Vec<&[u8]> = configs.iter().map(|config| config.as_slice()).collect();
+            decoder.config_raw_layers(&configs)?;
+        }
+
+        if let Some(conceal_method) = self.conceal_method {
+            decoder.set_conceal_method(conceal_method)?;
+        }
+
+        if let Some(limiter) = self.limiter {
+            decoder.set_limiter_enabled(Some(limiter))?;
+        }
+
+        if let Some(drc) = self.drc {
+            decoder.set_drc_boost(drc.boost)?;
+            decoder.set_drc_attenuation(drc.attenuation)?;
+            decoder.set_drc_reference_level(drc.reference_level)?;
+            decoder.set_drc_heavy_compression(drc.heavy_compression)?;
+        }
+
+        if let Some(drc_effect) = self.drc_effect {
+            decoder.set_drc_effect(drc_effect)?;
+        }
+
+        if let Some(channel_order) = self.channel_order {
+            decoder.set_channel_order(channel_order)?;
+        }
+
+        if let Some(output_channels) = self.output_channels {
+            decoder.set_output_channels(output_channels)?;
+        }
+
+        if let Some(dual_channel_mode) = self.dual_channel_mode {
+            decoder.set_dual_channel_mode(dual_channel_mode)?;
+        }
+
+        Ok(decoder)
+    }
+}
+
+// Decoding can't start right at the frame holding the target sample, the
+// frames before it are needed to rebuild the overlap and SBR state.
+const SEEK_PREROLL_FRAMES: u64 = 2;
+
+// Resume feeding at access unit number access_unit, then drop the first
+// discard samples per channel of the decoded output, which covers preroll,
+// decoder delay and the offset of the target within its frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeekPoint {
+    pub access_unit: u64,
+    pub discard: u64,
+}
+
+impl SeekPoint {
+    // target and delay are in samples per channel, frame_size is the
+    // decoded samples per channel of one access unit. None for a frame_size
+    // of 0, i.e. before the first frame was decoded.
+    pub fn new(target: u64, frame_size: usize, delay: usize) -> Option<Self> {
+        let frame_size = frame_size as u64;
+
+        // position of the target in the decoder output
+        let output_position = target + delay as u64;
+        let access_unit = output_position.checked_div(frame_size)?.saturating_sub(SEEK_PREROLL_FRAMES);
+
+        Some(SeekPoint {
+            access_unit,
+            discard: output_position - access_unit * frame_size,
+        })
+    }
+}
+
+// samples is the interleaved total.
+#[derive(Debug, Clone, Copy)]
+pub struct DecodeTotals {
+    pub frames: usize,
+    pub samples: usize,
+    pub output_size: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct PcmFrame {
+    // interleaved
+    pub samples: Vec<i16>,
+    pub channels: usize,
+    pub sample_rate: u32,
+}
+
+pub struct Frames<'a, R> {
+    decoder: &'a mut Decoder,
+    input: R,
+    input_buffer: Vec<u8>,
+    input_pos: usize,
+    input_len: usize,
+    pcm: Vec<i16>,
+    keep_id3_tag: bool,
+    id3_tag: Option<Vec<u8>>,
+    resync: bool,
+    searching: bool,
+    skipped_bytes: u64,
+    // per channel samples still to drop from the start, and to hold back
+    // in case they turn out to be the end padding
+    trim_start: u64,
+    trim_end: usize,
+    held: Vec<i16>,
+    eof: bool,
+    done: bool,
+}
+
+impl<'a, R: Read> Frames<'a, R> {
+    // An ID3v2 tag at the start of an ADTS or ADIF stream is always skipped,
+    // this keeps its bytes (header included) for id3_tag().
+    pub fn keep_id3_tag(mut self, keep: bool) -> Self {
+        self.keep_id3_tag = keep;
+        self
+    }
+
+    // Only available once the first frame has been read.
+    pub fn id3_tag(&self) -> Option<&[u8]> {
+        self.id3_tag.as_deref()
+    }
+
+    // Sync errors are always ridden out by the library. With resync enabled,
+    // ADTS and LOAS input also survives errors that would otherwise end the
+    // iteration: buffered input is dropped and decoding restarts at the next
+    // syncword.
+    pub fn resync(mut self, enabled: bool) -> Self {
+        self.resync = enabled;
+        self
+    }
+
+    // Drops the encoder priming from the start and the padding from the end
+    // of the decoded output, e.g. from iTunSMPB, an edit list or
+    // enc::GaplessInfo, both in samples per channel counted from the
+    // decoder output. Holds back padding samples until the end is known.
+    pub fn trim(mut self, priming: u64, padding: usize) -> Self {
+        self.trim_start = priming;
+        self.trim_end = padding;
+        self
+    }
+
+    // Bytes dropped while searching for a syncword after an error. Bytes the
+    // library skips on its own are counted in Decoder::stats().bad_bytes.
+    pub fn skipped_bytes(&self) -> u64 {
+        self.skipped_bytes
+    }
+
+    fn skip_id3_tag(&mut self) -> io::Result<()> {
+        let mut header = [0; ID3_HEADER_SIZE];
+        let mut len = 0;
+        while len < header.len() {
+            let read = self.input.read(&mut header[len..])?;
+            if read == 0 {
+                break;
+            }
+            len += read;
+        }
+
+        let size = match id3_tag_size(&header[..len]) {
+            Some(size) => size,
+            None => {
+                // not a tag, hand the bytes to the decoder
+                self.input_buffer[..len].copy_from_slice(&header[..len]);
+                self.input_pos = 0;
+                self.input_len = len;
+                return Ok(());
+            }
+        };
+
+        let mut body = (&mut self.input).take((size - ID3_HEADER_SIZE) as u64);
+        if self.keep_id3_tag {
+            let mut tag = header.to_vec();
+            body.read_to_end(&mut tag)?;
+            self.id3_tag = Some(tag);
+        } else {
+            io::copy(&mut body, &mut io::sink())?;
+        }
+
+        Ok(())
+    }
+
+    fn next_frame(&mut self) -> io::Result<Option<PcmFrame>> {
+        if self.pcm.is_empty() {
+            self.input_buffer = vec![0; INPUT_CHUNK_SIZE];
+            self.pcm = vec![0; MAX_OUTPUT_SAMPLES];
+
+            if matches!(self.decoder.transport, Transport::Adts | Transport::Adif) {
+                self.skip_id3_tag()?;
+            }
+        }
+
+        loop {
+            if self.searching {
+                self.search_sync();
+            }
+
+            if !self.searching && self.input_pos < self.input_len {
+                let consumed = self.decoder.feed(&self.input_buffer[self.input_pos..self.input_len])
+                    .map_err(decoder_error)?;
+                self.input_pos += consumed;
+            }
+
+            match self.decoder.decode_frame(&mut self.pcm) {
+                Ok(()) => return Ok(Some(self.frame(self.decoder.decoded_frame_size()))),
+                Err(DecoderError::NOT_ENOUGH_BITS) | Err(DecoderError::TRANSPORT_SYNC_ERROR) => {}
+                // the output holds concealed PCM for the broken frame
+                Err(e) if e.is_decode_error() => return Ok(Some(self.frame(self.decoder.decoded_frame_size()))),
+                Err(e) if self.can_resync(e) => {
+                    self.decoder.signal_discontinuity().map_err(decoder_error)?;
+                    self.searching = true;
+                    continue;
+                }
+                Err(e) => return Err(decoder_error(e)),
+            }
+
+            if self.eof {
+                if self.decoder.stats.frames_decoded == 0 {
+                    return Ok(None);
+                }
+
+                let samples = self.decoder.flush(&mut self.pcm).map_err(decoder_error)?;
+                self.done = true;
+                return Ok(Some(self.frame(samples)));
+            }
+
+            if self.input_pos == self.input_len || self.searching {
+                // keep what has not been fed yet, a syncword may straddle reads
+                self.input_buffer.copy_within(self.input_pos..self.input_len, 0);
+                self.input_len -= self.input_pos;
+                self.input_pos = 0;
+
+                let read = self.input.read(&mut self.input_buffer[self.input_len..])?;
+                self.input_len += read;
+                self.eof = read == 0;
+            }
+        }
+    }
+
+    fn next_trimmed(&mut self) -> io::Result<Option<PcmFrame>> {
+        loop {
+            let mut frame = match self.next_frame()? {
+                Some(frame) => frame,
+                // whatever is held back is the padding
+                None => return Ok(None),
+            };
+
+            if self.trim_start == 0 && self.trim_end == 0 {
+                return Ok(Some(frame));
+            }
+
+            let channels = frame.channels.max(1);
+            let drop = (self.trim_start as usize).saturating_mul(channels).min(frame.samples.len());
+            self.trim_start -= (drop / channels) as u64;
+
+            self.held.extend_from_slice(&frame.samples[drop..]);
+
+            let hold = self.trim_end * channels;
+            if self.held.len() > hold {
+                frame.samples = self.held.drain(..self.held.len() - hold).collect();
+                return Ok(Some(frame));
+            }
+
+            // the flush frame has been read, nothing after it
+            if self.done {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn can_resync(&self, e: DecoderError) -> bool {
+        let transport = self.decoder.transport;
+        self.resync &&
+            (transport == Transport::Adts || transport == Transport::Loas) &&
+            e != DecoderError::OUT_OF_MEMORY && e != DecoderError::INVALID_HANDLE
+    }
+
+    // Skips ahead to the next ADTS or LOAS syncword in the unfed input.
+    fn search_sync(&mut self) {
+        let input = &self.input_buffer[self.input_pos..self.input_len];
+        let found = input.windows(2).position(|bytes| match self.decoder.transport {
+            Transport::Adts => bytes[0] == 0xff && bytes[1] & 0xf6 == 0xf0,
+            _ => bytes[0] == 0x56 && bytes[1] & 0xe0 == 0xe0,
+        });
+
+        let skip = match found {
+            Some(offset) => {
+                self.searching = false;
+                offset
+            }
+            // the last byte could start a syncword
+            None => input.len().saturating_sub(1),
+        };
+
+        self.input_pos += skip;
+        self.skipped_bytes += skip as u64;
+    }
+
+    fn frame(&self, samples: usize) -> PcmFrame {
+        self.decoder.pcm_frame(&self.pcm[0..samples])
+    }
+}
+
+impl<'a, R: Read> Iterator for Frames<'a, R> {
+    type Item = io::Result<PcmFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.next_trimmed() {
+            Ok(Some(frame)) => Some(Ok(frame)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+pub(crate) fn decoder_error(e: DecoderError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+// Like the encoder, all decoder state lives behind the handle. Methods
+// taking &self only read from it.
 unsafe impl Send for Decoder {}
 unsafe impl Sync for Decoder {}
 
-impl Drop for Decoder {
-    fn drop(&mut self) {
-        unsafe { sys::aacDecoder_Close(self.handle); }
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    assert_send_sync::<Decoder>();
+};
+
+impl Debug for Decoder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Stream properties stay zero until the first frame has been decoded.
+        let stream_info = self.stream_info();
+
+        f.debug_struct("Decoder")
+            .field("transport", &self.transport)
+            .field("aot", &stream_info.aot)
+            .field("sample_rate", &stream_info.sample_rate)
+            .field("channels", &stream_info.channels)
+            .field("bit_rate", &stream_info.bit_rate)
+            .finish()
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+// frames_decoded doesn't count the output of flush. frames_concealed
+// includes frames that failed to decode and were replaced by concealment
+// output as well as decode_lost_frame calls. The byte and access unit
+// counts are as reported by the library.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DecoderStats {
+    pub frames_decoded: u64,
+    pub frames_concealed: u64,
+    pub errors: u64,
+    pub total_bytes: u64,
+    pub bad_bytes: u64,
+    pub total_access_units: u64,
+    pub bad_access_units: u64,
+}
+
+// samples is the interleaved total, frame_size the samples per channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeInfo {
+    pub samples: usize,
+    pub frame_size: usize,
+    pub channels: usize,
+    pub sample_rate: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputFormat {
+    pub sample_rate: u32,
+    pub channels: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeStatus {
+    // no complete frame is buffered, more input has to be fed first
+    NeedMoreData,
+    Frame,
+    // a frame was decoded, in a different format than the previous one
+    OutputFormatChanged(OutputFormat),
+}
+
+// aot and ext_aot are MPEG-4 audio object type numbers, e.g. 2 for AAC-LC,
+// 5 for SBR and 29 for PS. frame_size is per channel and includes SBR, so
+// 960 sample DAB+/DRM streams report 960 or 1920, aac_samples_per_frame is
+// that of the core coder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamInfo {
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub frame_size: usize,
+    pub aot: i32,
+    pub ext_aot: i32,
+    pub bit_rate: u32,
+    pub channel_config: u32,
+    pub aac_sample_rate: u32,
+    pub ext_sample_rate: u32,
+    pub aac_samples_per_frame: usize,
+    pub output_delay: usize,
+    // error protection config of ER streams, only 0 is supported. -1 for
+    // non ER streams.
+    pub ep_config: i32,
+}
+
+impl StreamInfo {
+    // ER AAC-LC, AAC-LD and AAC-ELD among others. These can't be carried
+    // in ADTS, they come as raw access units (Decoder::new_raw) or in LATM.
+    pub fn is_error_resilient(&self) -> bool {
+        (sys::AUDIO_OBJECT_TYPE_AOT_ER_AAC_LC..=sys::AUDIO_OBJECT_TYPE_AOT_ER_PARA).contains(&self.aot) ||
+            self.aot == sys::AUDIO_OBJECT_TYPE_AOT_ER_AAC_ELD
+    }
+
+    // xHE-AAC, which uses MPEG-D DRC (see Decoder::set_drc_effect).
+    pub fn is_usac(&self) -> bool {
+        self.aot == sys::AUDIO_OBJECT_TYPE_AOT_USAC
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelType {
+    None,
+    Front,
+    Side,
+    Back,
+    Lfe,
+    Top,
+    FrontTop,
+    SideTop,
+    BackTop,
+    Bottom,
+    FrontBottom,
+    SideBottom,
+    BackBottom,
+    Other(sys::AUDIO_CHANNEL_TYPE),
+}
+
+impl ChannelType {
+    fn from_raw(channel_type: sys::AUDIO_CHANNEL_TYPE) -> Self {
+        match channel_type {
+            sys::AUDIO_CHANNEL_TYPE_ACT_NONE => ChannelType::None,
+            sys::AUDIO_CHANNEL_TYPE_ACT_FRONT => ChannelType::Front,
+            sys::AUDIO_CHANNEL_TYPE_ACT_SIDE => ChannelType::Side,
+            sys::AUDIO_CHANNEL_TYPE_ACT_BACK => ChannelType::Back,
+            sys::AUDIO_CHANNEL_TYPE_ACT_LFE => ChannelType::Lfe,
+            sys::AUDIO_CHANNEL_TYPE_ACT_TOP => ChannelType::Top,
+            sys::AUDIO_CHANNEL_TYPE_ACT_FRONT_TOP => ChannelType::FrontTop,
+            sys::AUDIO_CHANNEL_TYPE_ACT_SIDE_TOP => ChannelType::SideTop,
+            sys::AUDIO_CHANNEL_TYPE_ACT_BACK_TOP => ChannelType::BackTop,
+            sys::AUDIO_CHANNEL_TYPE_ACT_BOTTOM => ChannelType::Bottom,
+            sys::AUDIO_CHANNEL_TYPE_ACT_FRONT_BOTTOM => ChannelType::FrontBottom,
+            sys::AUDIO_CHANNEL_TYPE_ACT_SIDE_BOTTOM => ChannelType::SideBottom,
+            sys::AUDIO_CHANNEL_TYPE_ACT_BACK_BOTTOM => ChannelType::BackBottom,
+            other => ChannelType::Other(other),
+        }
+    }
+}
+
+// index orders the channels of one type as in ISO/IEC 13818-7 8.5.3.2:
+// center first if present, then left/right pairs moving outwards. The 5.1
+// front is center 0, left 1, right 2, plain stereo is left 0, right 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelPosition {
+    pub channel_type: ChannelType,
+    pub index: u8,
+}
+
+// Which downmix metadata the decoder uses when mixing down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataProfile {
+    // the default, only the advanced downmix metadata from a DSE
+    MpegStandard,
+    // falls back to the PCE matrix mixdown when there is no DSE metadata,
+    // for legacy digital TV streams
+    MpegLegacy,
+    // like MpegLegacy, but the PCE matrix mixdown wins when both are present
+    MpegLegacyPrio,
+    // downmix as described in ABNT NBR 15602-2
+    AribJapan,
+}
+
+impl MetadataProfile {
+    fn profile(&self) -> sys::AAC_MD_PROFILE {
+        match self {
+            MetadataProfile::MpegStandard => sys::AAC_MD_PROFILE_AAC_MD_PROFILE_MPEG_STANDARD,
+            MetadataProfile::MpegLegacy => sys::AAC_MD_PROFILE_AAC_MD_PROFILE_MPEG_LEGACY,
+            MetadataProfile::MpegLegacyPrio => sys::AAC_MD_PROFILE_AAC_MD_PROFILE_MPEG_LEGACY_PRIO,
+            MetadataProfile::AribJapan => sys::AAC_MD_PROFILE_AAC_MD_PROFILE_ARIB_JAPAN,
+        }
+    }
+}
+
+// How the two programs of a dual mono (bilingual) stream are output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DualChannelMode {
+    // first program left, second program right
+    Both,
+    First,
+    Second,
+    Mix,
+}
+
+impl DualChannelMode {
+    fn mode(&self) -> i32 {
+        match self {
+            DualChannelMode::Both => 0,
+            DualChannelMode::First => 1,
+            DualChannelMode::Second => 2,
+            DualChannelMode::Mix => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConcealMethod {
+    Mute,
+    // spectral noise substitution
+    Noise,
+    // energy interpolation between the surrounding frames, adds a frame of delay
+    Interpolation,
+}
+
+impl ConcealMethod {
+    fn method(&self) -> i32 {
+        match self {
+            ConcealMethod::Mute => 0,
+            ConcealMethod::Noise => 1,
+            ConcealMethod::Interpolation => 2,
+        }
+    }
+}
+
+// MPEG-D DRC effect types, requested for selecting a DRC set in xHE-AAC streams
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DrcEffect {
+    // disables MPEG-D DRC completely
+    Off,
+    // the default, only applies DRC where needed to prevent clipping
+    None,
+    LateNight,
+    NoisyEnvironment,
+    LimitedPlaybackRange,
+    LowPlaybackLevel,
+    DialogEnhancement,
+    GeneralCompression,
+}
+
+impl DrcEffect {
+    fn effect(&self) -> i32 {
+        match self {
+            DrcEffect::Off => -1,
+            DrcEffect::None => 0,
+            DrcEffect::LateNight => 1,
+            DrcEffect::NoisyEnvironment => 2,
+            DrcEffect::LimitedPlaybackRange => 3,
+            DrcEffect::LowPlaybackLevel => 4,
+            DrcEffect::DialogEnhancement => 5,
+            DrcEffect::GeneralCompression => 6,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Transport {
     Adts,
+    // "ADIF" header at the start of the stream, followed by raw data
+    // blocks without any framing
+    Adif,
+    // StreamMuxConfig is carried in-band and picked up by the decoder
+    LatmMcp1,
+    // StreamMuxConfig is out of band and has to be passed to config_raw
+    LatmMcp0,
+    Loas,
+    // one access unit per fill, configured through config_raw. Also for
+    // DAB+, with the access units split out of the superframes and an
+    // AudioSpecificConfig signaling 960 sample frames.
+    Raw,
+    // DRM audio frames, configured through config_raw with the SDC audio
+    // config
+    Drm,
+}
+
+impl Transport {
+    fn needs_config(&self) -> bool {
+        matches!(self, Transport::LatmMcp0 | Transport::Raw | Transport::Drm)
+    }
+
+    fn transport_type(&self) -> sys::TRANSPORT_TYPE {
+        match self {
+            Transport::Adts => sys::TRANSPORT_TYPE_TT_MP4_ADTS,
+            Transport::Adif => sys::TRANSPORT_TYPE_TT_MP4_ADIF,
+            Transport::LatmMcp1 => sys::TRANSPORT_TYPE_TT_MP4_LATM_MCP1,
+            Transport::LatmMcp0 => sys::TRANSPORT_TYPE_TT_MP4_LATM_MCP0,
+            Transport::Loas => sys::TRANSPORT_TYPE_TT_MP4_LOAS,
+            Transport::Raw => sys::TRANSPORT_TYPE_TT_MP4_RAW,
+            Transport::Drm => sys::TRANSPORT_TYPE_TT_DRM,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seek_point_preroll() {
+        // 12048 into the output is frame 11, resumed two frames earlier
+        assert_eq!(SeekPoint::new(10000, 1024, 2048), Some(SeekPoint { access_unit: 9, discard: 12048 - 9 * 1024 }));
+        assert_eq!(SeekPoint::new(4096, 1024, 0), Some(SeekPoint { access_unit: 2, discard: 2048 }));
+    }
+
+    #[test]
+    fn seek_point_near_start() {
+        assert_eq!(SeekPoint::new(0, 1024, 0), Some(SeekPoint { access_unit: 0, discard: 0 }));
+        assert_eq!(SeekPoint::new(1500, 1024, 0), Some(SeekPoint { access_unit: 0, discard: 1500 }));
+        assert_eq!(SeekPoint::new(100, 2048, 3000), Some(SeekPoint { access_unit: 0, discard: 3100 }));
+    }
+
+    #[test]
+    fn seek_point_unknown_frame_size() {
+        assert_eq!(SeekPoint::new(10000, 0, 0), None);
+    }
 }
@@ -1,34 +1,36 @@
-use std::io::{Read, Write};
+use std::io::Write;
 use std::fmt::{self, Display, Debug};
-use std::mem::{self, MaybeUninit};
+use std::mem;
+#[cfg(feature = "native")]
+use std::io::{self, Read};
+#[cfg(feature = "native")]
+use std::mem::MaybeUninit;
+#[cfg(feature = "native")]
 use std::os::raw::{c_void, c_uint, c_int};
+#[cfg(feature = "native")]
 use std::ptr;
 
+#[cfg(feature = "native")]
 use fdk_aac_sys as sys;
 
-pub use sys::AACENC_InfoStruct as InfoStruct;
+#[cfg(feature = "native")]
+use crate::lib_info::{self, LibInfo};
 
 pub enum EncoderError {
     Io(std::io::Error),
-    FdkAac(sys::AACENC_ERROR),
+    // an AACENC_ERROR code
+    FdkAac(u32),
+    InvalidParams(&'static str),
+    Unsupported(&'static str),
 }
 
 impl EncoderError {
     fn message(&self) -> &'static str {
         match self {
-            EncoderError::FdkAac(sys::AACENC_ERROR_AACENC_INVALID_HANDLE) => "Handle passed to function call was invalid.",
-            EncoderError::FdkAac(sys::AACENC_ERROR_AACENC_MEMORY_ERROR) => "Memory allocation failed.",
-            EncoderError::FdkAac(sys::AACENC_ERROR_AACENC_UNSUPPORTED_PARAMETER) => "Parameter not available.",
-            EncoderError::FdkAac(sys::AACENC_ERROR_AACENC_INVALID_CONFIG) => "Configuration not provided.",
-            EncoderError::FdkAac(sys::AACENC_ERROR_AACENC_INIT_ERROR) => "General initialization error.",
-            EncoderError::FdkAac(sys::AACENC_ERROR_AACENC_INIT_AAC_ERROR) => "AAC library initialization error.",
-            EncoderError::FdkAac(sys::AACENC_ERROR_AACENC_INIT_SBR_ERROR) => "SBR library initialization error.",
-            EncoderError::FdkAac(sys::AACENC_ERROR_AACENC_INIT_TP_ERROR) => "Transport library initialization error.",
-            EncoderError::FdkAac(sys::AACENC_ERROR_AACENC_INIT_META_ERROR) => "Meta data library initialization error.",
-            EncoderError::FdkAac(sys::AACENC_ERROR_AACENC_INIT_MPS_ERROR) => "MPS library initialization error.",
-            EncoderError::FdkAac(sys::AACENC_ERROR_AACENC_ENCODE_ERROR) => "The encoding process was interrupted by an unexpected error.",
-            EncoderError::FdkAac(_) => "Unknown error",
+            EncoderError::FdkAac(code) => fdk_aac_message(*code),
             EncoderError::Io(_e) => "io error",
+            EncoderError::InvalidParams(msg) => msg,
+            EncoderError::Unsupported(msg) => msg,
         }
     }
 
@@ -36,10 +38,36 @@ impl EncoderError {
         match self {
             EncoderError::FdkAac(code) => *code,
             EncoderError::Io(_e) => 0,
+            EncoderError::InvalidParams(_) => 0,
+            EncoderError::Unsupported(_) => 0,
         }
     }
 }
 
+#[cfg(feature = "native")]
+fn fdk_aac_message(code: u32) -> &'static str {
+    match code {
+        sys::AACENC_ERROR_AACENC_INVALID_HANDLE => "Handle passed to function call was invalid.",
+        sys::AACENC_ERROR_AACENC_MEMORY_ERROR => "Memory allocation failed.",
+        sys::AACENC_ERROR_AACENC_UNSUPPORTED_PARAMETER => "Parameter not available.",
+        sys::AACENC_ERROR_AACENC_INVALID_CONFIG => "Configuration not provided.",
+        sys::AACENC_ERROR_AACENC_INIT_ERROR => "General initialization error.",
+        sys::AACENC_ERROR_AACENC_INIT_AAC_ERROR => "AAC library initialization error.",
+        sys::AACENC_ERROR_AACENC_INIT_SBR_ERROR => "SBR library initialization error.",
+        sys::AACENC_ERROR_AACENC_INIT_TP_ERROR => "Transport library initialization error.",
+        sys::AACENC_ERROR_AACENC_INIT_META_ERROR => "Meta data library initialization error.",
+        sys::AACENC_ERROR_AACENC_INIT_MPS_ERROR => "MPS library initialization error.",
+        sys::AACENC_ERROR_AACENC_ENCODE_ERROR => "The encoding process was interrupted by an unexpected error.",
+        _ => "Unknown error",
+    }
+}
+
+// Without the library no FdkAac errors are ever produced.
+#[cfg(not(feature = "native"))]
+fn fdk_aac_message(_code: u32) -> &'static str {
+    "Unknown error"
+}
+
 impl std::error::Error for EncoderError {
 }
 
@@ -61,6 +89,7 @@ impl From<std::io::Error> for EncoderError {
     }
 }
 
+#[cfg(feature = "native")]
 fn check(e: sys::AACENC_ERROR) -> Result<(), EncoderError> {
     if e == sys::AACENC_ERROR_AACENC_OK {
         Ok(())
@@ -69,10 +98,17 @@ fn check(e: sys::AACENC_ERROR) -> Result<(), EncoderError> {
     }
 }
 
+#[cfg(feature = "native")]
+pub fn lib_info() -> Result<Vec<LibInfo>, EncoderError> {
+    lib_info::collect(|table| check(unsafe { sys::aacEncGetLibInfo(table) }))
+}
+
+#[cfg(feature = "native")]
 struct EncoderHandle {
     ptr: sys::HANDLE_AACENCODER,
 }
 
+#[cfg(feature = "native")]
 impl EncoderHandle {
     pub fn alloc(max_modules: usize, max_channels: usize) -> Result<Self, EncoderError> {
         let mut ptr: sys::HANDLE_AACENCODER = ptr::null_mut();
@@ -83,6 +119,56 @@ impl EncoderHandle {
     }
 }
 
+#[cfg(feature = "native")]
+impl EncoderHandle {
+    fn info(&self) -> Result<EncoderInfo, EncoderError> {
+        let mut info: MaybeUninit<sys::AACENC_InfoStruct> = MaybeUninit::uninit();
+        check(unsafe { sys::aacEncInfo(self.ptr, info.as_mut_ptr()) })?;
+        let info = unsafe { info.assume_init() };
+
+        Ok(EncoderInfo {
+            frame_length: info.frameLength as usize,
+            input_channels: info.inputChannels as usize,
+            delay: info.nDelay as usize,
+            delay_core: info.nDelayCore as usize,
+            max_out_buf_bytes: info.maxOutBufBytes as usize,
+            max_anc_bytes: info.maxAncBytes as usize,
+            config: info.confBuf[0..info.confSize as usize].to_vec(),
+        })
+    }
+
+    fn set_param(&self, param: sys::AACENC_PARAM, value: u32) -> Result<(), EncoderError> {
+        check(unsafe { sys::aacEncoder_SetParam(self.ptr, param, value) })
+    }
+
+    fn get_param(&self, param: sys::AACENC_PARAM) -> u32 {
+        unsafe { sys::aacEncoder_GetParam(self.ptr, param) }
+    }
+
+    fn set_bitrate(&self, bit_rate: BitRate) -> Result<(), EncoderError> {
+        let bitrate_mode = match bit_rate {
+            BitRate::Cbr(bitrate) => {
+                self.set_param(sys::AACENC_PARAM_AACENC_BITRATE, bitrate)?;
+                0
+            }
+            BitRate::VbrVeryLow => 1,
+            BitRate::VbrLow => 2,
+            BitRate::VbrMedium => 3,
+            BitRate::VbrHigh => 4,
+            BitRate::VbrVeryHigh => 5,
+        };
+
+        self.set_param(sys::AACENC_PARAM_AACENC_BITRATEMODE, bitrate_mode)
+    }
+
+    // parameter changes only take effect after an encode call, which with
+    // all null params just reinitializes the encoder
+    fn reconfigure(&self) -> Result<(), EncoderError> {
+        check(unsafe { sys::aacEncEncode(self.ptr, ptr::null(), ptr::null(), ptr::null(), ptr::null_mut()) })
+    }
+}
+
+#[cfg(feature = "native")]
 impl Drop for EncoderHandle {
     fn drop(&mut self) {
         unsafe { sys::aacEncClose(&mut self.ptr as *mut _); }
@@ -90,6 +176,8 @@ impl Drop for EncoderHandle {
 }
 
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum BitRate {
     Cbr(u32),
     VbrVeryLow,
@@ -99,20 +187,667 @@ pub enum BitRate {
     VbrVeryHigh,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ChannelMode {
+    Mono,
+    Stereo,
+    Three,
+    Four,
+    Five,
+    FivePointOne,
+    SixPointOne,
+    SevenPointOne,
+    SevenPointOneBack,
+    SevenPointOneTopFront,
+    SevenPointOneRearSurround,
+    SevenPointOneFrontCenter,
+    // stereo coded as a mono downmix plus MPEG Surround side info, ELD only
+    TwoOneTwo,
+}
+
+impl ChannelMode {
+    #[cfg(feature = "native")]
+    fn mode(&self) -> sys::CHANNEL_MODE {
+        match self {
+            ChannelMode::Mono => sys::CHANNEL_MODE_MODE_1,
+            ChannelMode::Stereo => sys::CHANNEL_MODE_MODE_2,
+            ChannelMode::Three => sys::CHANNEL_MODE_MODE_1_2,
+            ChannelMode::Four => sys::CHANNEL_MODE_MODE_1_2_1,
+            ChannelMode::Five => sys::CHANNEL_MODE_MODE_1_2_2,
+            ChannelMode::FivePointOne => sys::CHANNEL_MODE_MODE_1_2_2_1,
+            ChannelMode::SixPointOne => sys::CHANNEL_MODE_MODE_6_1,
+            ChannelMode::SevenPointOne => sys::CHANNEL_MODE_MODE_1_2_2_2_1,
+            ChannelMode::SevenPointOneBack => sys::CHANNEL_MODE_MODE_7_1_BACK,
+            ChannelMode::SevenPointOneTopFront => sys::CHANNEL_MODE_MODE_7_1_TOP_FRONT,
+            ChannelMode::SevenPointOneRearSurround => sys::CHANNEL_MODE_MODE_7_1_REAR_SURROUND,
+            ChannelMode::SevenPointOneFrontCenter => sys::CHANNEL_MODE_MODE_7_1_FRONT_CENTER,
+            ChannelMode::TwoOneTwo => sys::CHANNEL_MODE_MODE_212,
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        match self {
+            ChannelMode::Mono => 1,
+            ChannelMode::Stereo | ChannelMode::TwoOneTwo => 2,
+            ChannelMode::Three => 3,
+            ChannelMode::Four => 4,
+            ChannelMode::Five => 5,
+            ChannelMode::FivePointOne => 6,
+            ChannelMode::SixPointOne => 7,
+            ChannelMode::SevenPointOne |
+            ChannelMode::SevenPointOneBack |
+            ChannelMode::SevenPointOneTopFront |
+            ChannelMode::SevenPointOneRearSurround |
+            ChannelMode::SevenPointOneFrontCenter => 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum AudioObjectType {
+    AacLc,
+    HeAac,
+    HeAacV2,
+    AacLd,
+    AacEld,
+    Usac,
+}
+
+#[cfg(feature = "native")]
+impl AudioObjectType {
+    fn aot(&self) -> sys::AUDIO_OBJECT_TYPE {
+        match self {
+            AudioObjectType::AacLc => sys::AUDIO_OBJECT_TYPE_AOT_AAC_LC,
+            AudioObjectType::HeAac => sys::AUDIO_OBJECT_TYPE_AOT_SBR,
+            AudioObjectType::HeAacV2 => sys::AUDIO_OBJECT_TYPE_AOT_PS,
+            AudioObjectType::AacLd => sys::AUDIO_OBJECT_TYPE_AOT_ER_AAC_LD,
+            AudioObjectType::AacEld => sys::AUDIO_OBJECT_TYPE_AOT_ER_AAC_ELD,
+            AudioObjectType::Usac => sys::AUDIO_OBJECT_TYPE_AOT_USAC,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum SbrMode {
+    Auto,
+    Off,
+    On,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum SbrRatio {
+    Downsampled,
+    DualRate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum SignalingMode {
+    Implicit,
+    ExplicitBackwardCompatible,
+    ExplicitHierarchical,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ChannelOrder {
+    Mpeg,
+    Wav,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct EncoderParams {
     pub bit_rate: BitRate,
     pub sample_rate: u32,
     pub transport: Transport,
+    pub channels: ChannelMode,
+    pub audio_object_type: AudioObjectType,
+    pub sbr: SbrMode,
+    pub sbr_ratio: Option<SbrRatio>,
+    pub signaling_mode: Option<SignalingMode>,
+    pub afterburner: bool,
+    pub bandwidth: Option<u32>,
+    pub frame_length: Option<u32>,
+    pub protection: bool,
+    pub header_period: Option<u32>,
+    pub peak_bitrate: Option<u32>,
+    pub channel_order: ChannelOrder,
+    pub audio_mux_version: Option<u32>,
+    pub tp_subframes: Option<u32>,
+    pub metadata_mode: MetadataMode,
+    pub ancillary_bitrate: Option<u32>,
+}
+
+impl Default for EncoderParams {
+    fn default() -> Self {
+        EncoderParams {
+            bit_rate: BitRate::Cbr(128000),
+            sample_rate: 44100,
+            transport: Transport::Adts,
+            channels: ChannelMode::Stereo,
+            audio_object_type: AudioObjectType::AacLc,
+            sbr: SbrMode::Auto,
+            sbr_ratio: None,
+            signaling_mode: None,
+            afterburner: true,
+            bandwidth: None,
+            frame_length: None,
+            protection: false,
+            header_period: None,
+            peak_bitrate: None,
+            channel_order: ChannelOrder::Mpeg,
+            audio_mux_version: None,
+            tp_subframes: None,
+            metadata_mode: MetadataMode::None,
+            ancillary_bitrate: None,
+        }
+    }
+}
+
+impl EncoderParams {
+    pub fn builder() -> EncoderParamsBuilder {
+        EncoderParamsBuilder { params: EncoderParams::default() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Preset {
+    VoiceMono16k,
+    MusicStereo44k,
+    MusicLowBitrate,
+    BroadcastStereo48k,
+    LowLatencyStereo48k,
+}
+
+impl Preset {
+    pub fn params(&self) -> EncoderParams {
+        let builder = EncoderParams::builder();
+
+        let builder = match self {
+            Preset::VoiceMono16k => builder
+                .bit_rate(BitRate::Cbr(24000))
+                .sample_rate(16000)
+                .channels(ChannelMode::Mono)
+                .bandwidth(7000),
+            Preset::MusicStereo44k => builder
+                .bit_rate(BitRate::VbrHigh)
+                .sample_rate(44100),
+            Preset::MusicLowBitrate => builder
+                .bit_rate(BitRate::Cbr(32000))
+                .sample_rate(44100)
+                .audio_object_type(AudioObjectType::HeAacV2),
+            Preset::BroadcastStereo48k => builder
+                .bit_rate(BitRate::Cbr(192000))
+                .sample_rate(48000)
+                .protection(true),
+            Preset::LowLatencyStereo48k => builder
+                .bit_rate(BitRate::Cbr(96000))
+                .sample_rate(48000)
+                .audio_object_type(AudioObjectType::AacEld)
+                .frame_length(480)
+                .transport(Transport::Raw),
+        };
+
+        builder.params
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EncoderParamsBuilder {
+    params: EncoderParams,
+}
+
+impl EncoderParamsBuilder {
+    pub fn bit_rate(mut self, bit_rate: BitRate) -> Self {
+        self.params.bit_rate = bit_rate;
+        self
+    }
+
+    pub fn sample_rate(mut self, sample_rate: u32) -> Self {
+        self.params.sample_rate = sample_rate;
+        self
+    }
+
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.params.transport = transport;
+        self
+    }
+
+    pub fn channels(mut self, channels: ChannelMode) -> Self {
+        self.params.channels = channels;
+        self
+    }
+
+    pub fn audio_object_type(mut self, audio_object_type: AudioObjectType) -> Self {
+        self.params.audio_object_type = audio_object_type;
+        self
+    }
+
+    pub fn sbr(mut self, sbr: SbrMode) -> Self {
+        self.params.sbr = sbr;
+        self
+    }
+
+    pub fn sbr_ratio(mut self, sbr_ratio: SbrRatio) -> Self {
+        self.params.sbr_ratio = Some(sbr_ratio);
+        self
+    }
+
+    pub fn signaling_mode(mut self, signaling_mode: SignalingMode) -> Self {
+        self.params.signaling_mode = Some(signaling_mode);
+        self
+    }
+
+    pub fn afterburner(mut self, afterburner: bool) -> Self {
+        self.params.afterburner = afterburner;
+        self
+    }
+
+    pub fn bandwidth(mut self, bandwidth: u32) -> Self {
+        self.params.bandwidth = Some(bandwidth);
+        self
+    }
+
+    pub fn frame_length(mut self, frame_length: u32) -> Self {
+        self.params.frame_length = Some(frame_length);
+        self
+    }
+
+    pub fn protection(mut self, protection: bool) -> Self {
+        self.params.protection = protection;
+        self
+    }
+
+    pub fn header_period(mut self, header_period: u32) -> Self {
+        self.params.header_period = Some(header_period);
+        self
+    }
+
+    pub fn peak_bitrate(mut self, peak_bitrate: u32) -> Self {
+        self.params.peak_bitrate = Some(peak_bitrate);
+        self
+    }
+
+    pub fn channel_order(mut self, channel_order: ChannelOrder) -> Self {
+        self.params.channel_order = channel_order;
+        self
+    }
+
+    pub fn audio_mux_version(mut self, audio_mux_version: u32) -> Self {
+        self.params.audio_mux_version = Some(audio_mux_version);
+        self
+    }
+
+    pub fn tp_subframes(mut self, tp_subframes: u32) -> Self {
+        self.params.tp_subframes = Some(tp_subframes);
+        self
+    }
+
+    pub fn metadata_mode(mut self, metadata_mode: MetadataMode) -> Self {
+        self.params.metadata_mode = metadata_mode;
+        self
+    }
+
+    pub fn ancillary_bitrate(mut self, ancillary_bitrate: u32) -> Self {
+        self.params.ancillary_bitrate = Some(ancillary_bitrate);
+        self
+    }
+
+    pub fn build(self) -> Result<EncoderParams, EncoderError> {
+        validate(&self.params)?;
+        Ok(self.params)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseParamsError {
+    Empty,
+    UnknownToken(String),
+    InvalidBitRate(String),
+    Duplicate(&'static str, String),
+    Invalid(&'static str),
+}
+
+impl Display for ParseParamsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseParamsError::Empty => write!(f, "empty encoder configuration"),
+            ParseParamsError::UnknownToken(token) => write!(f, "unknown token {:?}, expected an audio object type, bitrate, sample rate, channel mode or transport", token),
+            ParseParamsError::InvalidBitRate(token) => write!(f, "invalid bitrate {:?}, expected cbr:<bps>[k] or vbr:<1-5>", token),
+            ParseParamsError::Duplicate(field, token) => write!(f, "{} given more than once (at {:?})", field, token),
+            ParseParamsError::Invalid(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ParseParamsError {
+}
+
+fn parse_bit_rate(token: &str) -> Option<BitRate> {
+    let (mode, value) = token.split_at(token.find(':')?);
+    let value = &value[1..];
+
+    match mode {
+        "cbr" => {
+            let (digits, scale) = match value.strip_suffix('k') {
+                Some(digits) => (digits, 1000),
+                None => (value, 1),
+            };
+            digits.parse::<u32>().ok()?.checked_mul(scale).map(BitRate::Cbr)
+        }
+        "vbr" => match value {
+            "1" | "very_low" => Some(BitRate::VbrVeryLow),
+            "2" | "low" => Some(BitRate::VbrLow),
+            "3" | "medium" => Some(BitRate::VbrMedium),
+            "4" | "high" => Some(BitRate::VbrHigh),
+            "5" | "very_high" => Some(BitRate::VbrVeryHigh),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Comma separated and order independent, e.g. "he_aac_v2,cbr:64k,44100,stereo,adts".
+// Anything not given is taken from EncoderParams::default().
+impl std::str::FromStr for EncoderParams {
+    type Err = ParseParamsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut params = EncoderParams::default();
+        let mut seen = [false; 5];
+
+        let mut mark = |index: usize, field: &'static str, token: &str| {
+            if mem::replace(&mut seen[index], true) {
+                Err(ParseParamsError::Duplicate(field, token.to_owned()))
+            } else {
+                Ok(())
+            }
+        };
+
+        if s.trim().is_empty() {
+            return Err(ParseParamsError::Empty);
+        }
+
+        for token in s.split(',').map(str::trim) {
+            let lower = token.to_ascii_lowercase();
+
+            let audio_object_type = match lower.as_str() {
+                "aac_lc" | "lc" => Some(AudioObjectType::AacLc),
+                "he_aac" | "he" => Some(AudioObjectType::HeAac),
+                "he_aac_v2" | "hev2" => Some(AudioObjectType::HeAacV2),
+                "aac_ld" | "ld" => Some(AudioObjectType::AacLd),
+                "aac_eld" | "eld" => Some(AudioObjectType::AacEld),
+                "usac" | "xhe_aac" => Some(AudioObjectType::Usac),
+                _ => None,
+            };
+            if let Some(audio_object_type) = audio_object_type {
+                mark(0, "audio object type", token)?;
+                params.audio_object_type = audio_object_type;
+                continue;
+            }
+
+            let channels = match lower.as_str() {
+                "mono" => Some(ChannelMode::Mono),
+                "stereo" => Some(ChannelMode::Stereo),
+                "three" => Some(ChannelMode::Three),
+                "four" => Some(ChannelMode::Four),
+                "five" => Some(ChannelMode::Five),
+                "five_point_one" | "5.1" => Some(ChannelMode::FivePointOne),
+                "six_point_one" | "6.1" => Some(ChannelMode::SixPointOne),
+                "seven_point_one" | "7.1" => Some(ChannelMode::SevenPointOne),
+                "seven_point_one_back" => Some(ChannelMode::SevenPointOneBack),
+                "seven_point_one_top_front" => Some(ChannelMode::SevenPointOneTopFront),
+                "seven_point_one_rear_surround" => Some(ChannelMode::SevenPointOneRearSurround),
+                "seven_point_one_front_center" => Some(ChannelMode::SevenPointOneFrontCenter),
+                "two_one_two" => Some(ChannelMode::TwoOneTwo),
+                _ => None,
+            };
+            if let Some(channels) = channels {
+                mark(1, "channel mode", token)?;
+                params.channels = channels;
+                continue;
+            }
+
+            let transport = match lower.as_str() {
+                "adts" => Some(Transport::Adts),
+                "adif" => Some(Transport::Adif),
+                "raw" => Some(Transport::Raw),
+                "latm_mcp1" | "latm" => Some(Transport::LatmMcp1),
+                "latm_mcp0" => Some(Transport::LatmMcp0),
+                "loas" => Some(Transport::Loas),
+                "dab_plus" => Some(Transport::DabPlus),
+                "drm" => Some(Transport::Drm),
+                _ => None,
+            };
+            if let Some(transport) = transport {
+                mark(2, "transport", token)?;
+                params.transport = transport;
+                continue;
+            }
+
+            if lower.starts_with("cbr:") || lower.starts_with("vbr:") {
+                let bit_rate = parse_bit_rate(&lower)
+                    .ok_or_else(|| ParseParamsError::InvalidBitRate(token.to_owned()))?;
+                mark(3, "bitrate", token)?;
+                params.bit_rate = bit_rate;
+                continue;
+            }
+
+            if let Ok(sample_rate) = lower.parse::<u32>() {
+                mark(4, "sample rate", token)?;
+                params.sample_rate = sample_rate;
+                continue;
+            }
+
+            return Err(ParseParamsError::UnknownToken(token.to_owned()));
+        }
+
+        validate(&params).map_err(|e| ParseParamsError::Invalid(e.message()))?;
+        Ok(params)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum MetadataMode {
+    None,
+    DynamicRange,
+    DynamicRangeAndAncillary,
+    Ancillary,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum DrcProfile {
+    None,
+    FilmStandard,
+    FilmLight,
+    MusicStandard,
+    MusicLight,
+    Speech,
+    NotPresent,
+}
+
+#[cfg(feature = "native")]
+impl DrcProfile {
+    fn profile(&self) -> sys::AACENC_METADATA_DRC_PROFILE {
+        match self {
+            DrcProfile::None => sys::AACENC_METADATA_DRC_PROFILE_AACENC_METADATA_DRC_NONE,
+            DrcProfile::FilmStandard => sys::AACENC_METADATA_DRC_PROFILE_AACENC_METADATA_DRC_FILMSTANDARD,
+            DrcProfile::FilmLight => sys::AACENC_METADATA_DRC_PROFILE_AACENC_METADATA_DRC_FILMLIGHT,
+            DrcProfile::MusicStandard => sys::AACENC_METADATA_DRC_PROFILE_AACENC_METADATA_DRC_MUSICSTANDARD,
+            DrcProfile::MusicLight => sys::AACENC_METADATA_DRC_PROFILE_AACENC_METADATA_DRC_MUSICLIGHT,
+            DrcProfile::Speech => sys::AACENC_METADATA_DRC_PROFILE_AACENC_METADATA_DRC_SPEECH,
+            DrcProfile::NotPresent => sys::AACENC_METADATA_DRC_PROFILE_AACENC_METADATA_DRC_NOT_PRESENT,
+        }
+    }
+}
+
+// Levels are in dB, mix levels are the 0..7 table indices from the spec.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MetaData {
+    pub drc_profile: DrcProfile,
+    pub comp_profile: DrcProfile,
+    pub drc_target_ref_level: f32,
+    pub comp_target_ref_level: f32,
+    pub prog_ref_level: Option<f32>,
+    pub pce_mixdown_present: bool,
+    pub etsi_mixdown_present: bool,
+    pub center_mix_level: i8,
+    pub surround_mix_level: i8,
+    pub dolby_surround_mode: u8,
+    pub drc_presentation_mode: u8,
+}
+
+#[cfg(feature = "native")]
+impl MetaData {
+    fn raw(&self) -> sys::AACENC_MetaData {
+        // levels are passed to the library as fixed point with 16 fractional bits
+        let level = |db: f32| (db * 65536.0) as i32;
+
+        let mut metadata: sys::AACENC_MetaData = unsafe { mem::zeroed() };
+        metadata.drc_profile = self.drc_profile.profile();
+        metadata.comp_profile = self.comp_profile.profile();
+        metadata.drc_TargetRefLevel = level(self.drc_target_ref_level);
+        metadata.comp_TargetRefLevel = level(self.comp_target_ref_level);
+        metadata.prog_ref_level_present = self.prog_ref_level.is_some() as i32;
+        metadata.prog_ref_level = level(self.prog_ref_level.unwrap_or(0.0));
+        metadata.PCE_mixdown_idx_present = self.pce_mixdown_present as u8;
+        metadata.ETSI_DmxLvl_present = self.etsi_mixdown_present as u8;
+        metadata.centerMixLevel = self.center_mix_level;
+        metadata.surroundMixLevel = self.surround_mix_level;
+        metadata.dolbySurroundMode = self.dolby_surround_mode;
+        metadata.drcPresentationMode = self.drc_presentation_mode;
+        metadata
+    }
+}
+
+#[cfg(feature = "native")]
+struct BufDesc {
+    bufs: [*mut c_void; 3],
+    identifiers: [c_int; 3],
+    sizes: [c_int; 3],
+    el_sizes: [c_int; 3],
+    count: usize,
+}
+
+#[cfg(feature = "native")]
+impl BufDesc {
+    fn new() -> Self {
+        BufDesc {
+            bufs: [ptr::null_mut(); 3],
+            identifiers: [0; 3],
+            sizes: [0; 3],
+            el_sizes: [0; 3],
+            count: 0,
+        }
+    }
+
+    fn push(&mut self, identifier: sys::AACENC_BufferIdentifier, buf: *mut c_void, size: usize, el_size: usize) {
+        self.bufs[self.count] = buf;
+        self.identifiers[self.count] = identifier as c_int;
+        self.sizes[self.count] = size as c_int;
+        self.el_sizes[self.count] = el_size as c_int;
+        self.count += 1;
+    }
+
+    fn as_sys(&mut self) -> sys::AACENC_BufDesc {
+        sys::AACENC_BufDesc {
+            numBufs: self.count as c_int,
+            bufs: self.bufs.as_mut_ptr(),
+            bufferIdentifiers: self.identifiers.as_mut_ptr(),
+            bufSizes: self.sizes.as_mut_ptr(),
+            bufElSizes: self.el_sizes.as_mut_ptr(),
+        }
+    }
 }
 
+#[cfg(feature = "native")]
 pub struct Encoder {
     handle: EncoderHandle,
+    params: EncoderParams,
+    // cached after every reconfiguration
+    info: EncoderInfo,
+    metadata: Option<sys::AACENC_MetaData>,
+    ancillary: Vec<u8>,
+    scratch: Vec<i16>,
+    samples_encoded: u64,
+    frames_encoded: u64,
+    // set by flush, the next input starts a new stream
+    flushed: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Transport {
     Adts,
+    // a single ADIF header at the start followed by raw access units
+    Adif,
     Raw,
+    LatmMcp1,
+    LatmMcp0,
+    Loas,
+    DabPlus,
+    Drm,
+}
+
+#[cfg(feature = "native")]
+impl Transport {
+    fn transmux(&self) -> sys::TRANSPORT_TYPE {
+        match self {
+            Transport::Adts => sys::TRANSPORT_TYPE_TT_MP4_ADTS,
+            Transport::Adif => sys::TRANSPORT_TYPE_TT_MP4_ADIF,
+            Transport::Raw => sys::TRANSPORT_TYPE_TT_MP4_RAW,
+            Transport::LatmMcp1 => sys::TRANSPORT_TYPE_TT_MP4_LATM_MCP1,
+            Transport::LatmMcp0 => sys::TRANSPORT_TYPE_TT_MP4_LATM_MCP0,
+            Transport::Loas => sys::TRANSPORT_TYPE_TT_MP4_LOAS,
+            // libfdk-aac has no DAB+ superframing, so this emits raw AUs
+            // to be packed into superframes by the multiplexer
+            Transport::DabPlus => sys::TRANSPORT_TYPE_TT_MP4_RAW,
+            Transport::Drm => sys::TRANSPORT_TYPE_TT_DRM,
+        }
+    }
+}
+
+// config holds the AudioSpecificConfig, or the StreamMuxConfig for LATM/LOAS
+#[derive(Debug, Clone)]
+pub struct EncoderInfo {
+    pub frame_length: usize,
+    pub input_channels: usize,
+    pub delay: usize,
+    pub delay_core: usize,
+    pub max_out_buf_bytes: usize,
+    pub max_anc_bytes: usize,
+    pub config: Vec<u8>,
+}
+
+// Sample counts are per channel, priming and padding have to be trimmed
+// from the start and end of the decoded output. priming is the full
+// encoder delay (EncoderInfo::delay), with SBR including the QMF delay, not
+// just that of the core coder, and is what players have to skip.
+#[derive(Debug, Clone, Copy)]
+pub struct GaplessInfo {
+    pub priming: usize,
+    pub padding: usize,
+    pub samples: u64,
 }
 
 #[derive(Debug)]
@@ -121,136 +856,1091 @@ pub struct EncodeInfo {
     pub output_size: usize,
 }
 
-impl Encoder {
-    pub fn new(params: EncoderParams) -> Result<Self, EncoderError> {
-        let handle = EncoderHandle::alloc(0, 2 /* hardcode stereo */)?;
+const SAMPLE_RATES: &[u32] = &[8000, 11025, 12000, 16000, 22050, 24000, 32000, 44100, 48000, 64000, 88200, 96000];
+const SBR_SAMPLE_RATES: &[u32] = &[16000, 22050, 24000, 32000, 44100, 48000];
+const ELD_SAMPLE_RATES: &[u32] = &[8000, 11025, 12000, 16000, 22050, 24000, 32000, 44100, 48000];
 
-        unsafe {
-            // hardcode MPEG-4 AAC Low Complexity for now:
-            check(sys::aacEncoder_SetParam(handle.ptr, sys::AACENC_PARAM_AACENC_AOT, 2))?;
+// For AAC-ELD these are the rates without SBR, with SbrMode::On only
+// 16 to 48 kHz work.
+pub fn supported_sample_rates(aot: AudioObjectType) -> &'static [u32] {
+    match aot {
+        AudioObjectType::AacLc |
+        AudioObjectType::AacLd => SAMPLE_RATES,
+        AudioObjectType::AacEld => ELD_SAMPLE_RATES,
+        AudioObjectType::HeAac |
+        AudioObjectType::HeAacV2 |
+        AudioObjectType::Usac => SBR_SAMPLE_RATES,
+    }
+}
 
-            let bitrate_mode = match params.bit_rate {
-                BitRate::Cbr(bitrate) => {
-                    check(sys::aacEncoder_SetParam(handle.ptr, sys::AACENC_PARAM_AACENC_BITRATE, bitrate))?;
-                    0
-                }
-                BitRate::VbrVeryLow => 1,
-                BitRate::VbrLow => 2,
-                BitRate::VbrMedium => 3,
-                BitRate::VbrHigh => 4,
-                BitRate::VbrVeryHigh => 5,
-            };
+pub fn bitrate_range(aot: AudioObjectType, channels: ChannelMode, sample_rate: u32) -> Option<(u32, u32)> {
+    if !supported_sample_rates(aot).contains(&sample_rate) {
+        return None;
+    }
 
-            check(sys::aacEncoder_SetParam(handle.ptr, sys::AACENC_PARAM_AACENC_BITRATEMODE, bitrate_mode))?;
+    let channels = channels.channels() as u32;
 
-            check(sys::aacEncoder_SetParam(handle.ptr, sys::AACENC_PARAM_AACENC_SAMPLERATE, params.sample_rate))?;
+    // AAC frames carry at most 6144 bits per channel, with SBR the core runs
+    // at half the rate and with PS it codes a mono downmix
+    let max_bitrate = match aot {
+        AudioObjectType::HeAac => (6 * sample_rate / 2 * channels).min(64000 * channels),
+        AudioObjectType::HeAacV2 => (6 * sample_rate / 2).min(64000),
+        _ => 6 * sample_rate * channels,
+    };
 
-            check(sys::aacEncoder_SetParam(handle.ptr, sys::AACENC_PARAM_AACENC_TRANSMUX, match params.transport {
-                Transport::Adts => 2,
-                Transport::Raw => 0,
-            }))?;
+    Some((8000, max_bitrate))
+}
 
-            // hardcode SBR off for now
-            check(sys::aacEncoder_SetParam(handle.ptr, sys::AACENC_PARAM_AACENC_SBR_MODE, 0))?;
+pub(crate) fn validate(params: &EncoderParams) -> Result<(), EncoderError> {
+    if params.audio_object_type == AudioObjectType::AacEld && params.sbr == SbrMode::On &&
+        !SBR_SAMPLE_RATES.contains(&params.sample_rate) {
+        return Err(EncoderError::InvalidParams("AAC-ELD with SBR requires a sample rate of 16 to 48 kHz."));
+    }
 
-            // hardcode stereo
-            check(sys::aacEncoder_SetParam(handle.ptr, sys::AACENC_PARAM_AACENC_CHANNELMODE, 2))?;
+    let (min_bitrate, max_bitrate) = match bitrate_range(params.audio_object_type, params.channels, params.sample_rate) {
+        Some(range) => range,
+        None => return Err(EncoderError::InvalidParams("Sample rate is not supported by the audio object type.")),
+    };
 
-            // call encode once with all null params according to docs
-            check(sys::aacEncEncode(handle.ptr, ptr::null(), ptr::null(), ptr::null(), ptr::null_mut()))?;
+    if let BitRate::Cbr(bitrate) = params.bit_rate {
+        if bitrate < min_bitrate || bitrate > max_bitrate {
+            return Err(EncoderError::InvalidParams("Bitrate is out of range for the audio object type, sample rate and channel mode."));
         }
-
-        Ok(Encoder { handle })
     }
 
-    pub fn info(&self) -> Result<InfoStruct, EncoderError> {
-        let mut info = MaybeUninit::uninit();
-        check(unsafe { sys::aacEncInfo(self.handle.ptr, info.as_mut_ptr()) })?;
-        Ok(unsafe { info.assume_init() })
-    }
+    if params.audio_object_type == AudioObjectType::Usac {
+        if params.channels != ChannelMode::Mono && params.channels != ChannelMode::Stereo {
+            return Err(EncoderError::InvalidParams("xHE-AAC only supports mono or stereo."));
+        }
 
-    pub fn encode<R: Read, W: Write>(&self, input: &mut R, output: &mut W) -> Result<EncodeInfo, EncoderError> {
+        match params.bit_rate {
+            BitRate::Cbr(_) => {}
+            _ => return Err(EncoderError::InvalidParams("xHE-AAC only supports constant bitrate encoding.")),
+        }
+    }
 
-        let info = self.info()?;
+    if params.channels == ChannelMode::TwoOneTwo && params.audio_object_type != AudioObjectType::AacEld {
+        return Err(EncoderError::InvalidParams("MPEG Surround 212 is only supported with AAC-ELD."));
+    }
 
-        let channels = 2; // hard-coded to stereo
-        let buffer_len = 2*channels*info.frameLength as usize;
-        let mut input_buffer = vec![0; buffer_len];
-        let mut output_buffer = vec![0; buffer_len];
+    if params.audio_object_type == AudioObjectType::HeAacV2 {
+        // parametric stereo codes a mono downmix plus stereo side info
+        if params.channels != ChannelMode::Stereo {
+            return Err(EncoderError::InvalidParams("HE-AACv2 requires stereo channel mode."));
+        }
+    }
 
-        let mut total_consumed_samples = 0;
-        let mut total_written_bytes = 0;
-        loop {
-            let input_len = input.read(&mut input_buffer)?;
-            if input_len == 0 {
-                break;
+    if let Some(frame_length) = params.frame_length {
+        match params.audio_object_type {
+            AudioObjectType::AacLd | AudioObjectType::AacEld => {
+                if frame_length != 512 && frame_length != 480 {
+                    return Err(EncoderError::InvalidParams("AAC-LD and AAC-ELD require a frame length of 512 or 480 samples."));
+                }
             }
+            _ => {
+                if frame_length != 1024 && frame_length != 960 {
+                    return Err(EncoderError::InvalidParams("Frame length must be 1024 or 960 samples."));
+                }
+            }
+        }
+    }
 
-            let mut input_buf = input_buffer.as_ptr() as *mut i16;
-            let mut input_buf_ident: c_int = sys::AACENC_BufferIdentifier_IN_AUDIO_DATA as c_int;
-            let mut input_buf_size: c_int = input_len as c_int;
-            let mut input_buf_el_size: c_int = mem::size_of::<i16>() as c_int;
-            let input_desc = sys::AACENC_BufDesc {
-                numBufs: 1,
-                bufs: &mut input_buf as *mut _ as *mut *mut c_void,
-                bufferIdentifiers: &mut input_buf_ident as *mut c_int,
-                bufSizes: &mut input_buf_size as *mut c_int,
-                bufElSizes: &mut input_buf_el_size as *mut c_int,
-            };
+    if params.audio_mux_version.is_some() || params.tp_subframes.is_some() {
+        match params.transport {
+            Transport::LatmMcp1 | Transport::LatmMcp0 | Transport::Loas => {}
+            _ => return Err(EncoderError::InvalidParams("AudioMuxVersion and TP subframes are only supported with LATM/LOAS transports.")),
+        }
+    }
 
-            let mut output_buf = output_buffer.as_mut_ptr();
-            let mut output_buf_ident: c_int = sys::AACENC_BufferIdentifier_OUT_BITSTREAM_DATA as c_int;
-            let mut output_buf_size: c_int = output_buffer.len() as c_int;
-            let mut output_buf_el_size: c_int = mem::size_of::<i16>() as c_int;
-            let output_desc = sys::AACENC_BufDesc {
-                numBufs: 1,
-                bufs: &mut output_buf as *mut _ as *mut *mut c_void,
-                bufferIdentifiers: &mut output_buf_ident as *mut _,
-                bufSizes: &mut output_buf_size as *mut _,
-                bufElSizes: &mut output_buf_el_size as *mut _,
-            };
+    if let Some(audio_mux_version) = params.audio_mux_version {
+        if audio_mux_version > 2 {
+            return Err(EncoderError::InvalidParams("AudioMuxVersion must be 0, 1 or 2."));
+        }
+    }
 
-            let in_args = sys::AACENC_InArgs {
-                numInSamples: input_len as i32 / 2,
-                numAncBytes: 0,
-            };
+    if params.transport == Transport::DabPlus {
+        if params.frame_length != Some(960) {
+            return Err(EncoderError::InvalidParams("DAB+ requires a frame length of 960 samples."));
+        }
 
-            let mut out_args = unsafe { mem::zeroed() };
+        if params.sample_rate != 32000 && params.sample_rate != 48000 {
+            return Err(EncoderError::InvalidParams("DAB+ requires a sample rate of 32 or 48 kHz."));
+        }
 
-            let code = unsafe {
-                sys::aacEncEncode(
-                    self.handle.ptr,
-                    &input_desc,
-                    &output_desc,
-                    &in_args,
-                    &mut out_args,
-                )
-            };
+        if params.channels != ChannelMode::Mono && params.channels != ChannelMode::Stereo {
+            return Err(EncoderError::InvalidParams("DAB+ only supports mono or stereo."));
+        }
 
-            if code != sys::AACENC_ERROR_AACENC_OK {
-                if code == sys::AACENC_ERROR_AACENC_ENCODE_EOF {
-                    break;
+        match params.audio_object_type {
+            AudioObjectType::AacLc => {}
+            AudioObjectType::HeAac | AudioObjectType::HeAacV2 => {
+                // the AAC core has to run at half the sample rate
+                if params.sbr_ratio == Some(SbrRatio::Downsampled) {
+                    return Err(EncoderError::InvalidParams("DAB+ requires dual-rate SBR."));
                 }
-
-                return Err(EncoderError::FdkAac(code));
             }
+            _ => return Err(EncoderError::InvalidParams("DAB+ only supports AAC-LC, HE-AAC and HE-AACv2.")),
+        }
+    }
 
-            let input_consumed = out_args.numInSamples as usize;
-            let output_size = out_args.numOutBytes as usize;
-            output.write(&output_buffer[0..output_size])?;
-            total_consumed_samples += input_consumed;
-            total_written_bytes += output_size;
+    if params.transport == Transport::Drm {
+        match params.audio_object_type {
+            AudioObjectType::Usac => {}
+            AudioObjectType::AacLc | AudioObjectType::HeAac | AudioObjectType::HeAacV2 => {
+                if params.frame_length != Some(960) {
+                    return Err(EncoderError::InvalidParams("DRM requires a frame length of 960 samples for AAC."));
+                }
+            }
+            _ => return Err(EncoderError::InvalidParams("DRM only supports AAC-LC, HE-AAC, HE-AACv2 and xHE-AAC.")),
         }
 
-        Ok(EncodeInfo {
-            output_size: total_written_bytes,
-            input_consumed: total_consumed_samples,
-        })
+        match params.sample_rate {
+            12000 | 24000 | 48000 => {}
+            _ => return Err(EncoderError::InvalidParams("DRM requires a sample rate of 12, 24 or 48 kHz.")),
+        }
     }
+
+    Ok(())
 }
 
-impl Debug for Encoder {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Encoder {{ handle: {:?} }}", self.handle.ptr)
+#[cfg(feature = "native")]
+impl Encoder {
+    pub fn new(params: EncoderParams) -> Result<Self, EncoderError> {
+        validate(&params)?;
+
+        let handle = EncoderHandle::alloc(0, params.channels.channels())?;
+
+        unsafe {
+            match check(sys::aacEncoder_SetParam(handle.ptr, sys::AACENC_PARAM_AACENC_AOT, params.audio_object_type.aot() as u32)) {
+                Err(EncoderError::FdkAac(_)) if params.audio_object_type == AudioObjectType::Usac => {
+                    return Err(EncoderError::Unsupported("The linked libfdk-aac was built without xHE-AAC (USAC) support."));
+                }
+                result => result?,
+            }
+
+            handle.set_bitrate(params.bit_rate)?;
+
+            if let Some(peak_bitrate) = params.peak_bitrate {
+                check(sys::aacEncoder_SetParam(handle.ptr, sys::AACENC_PARAM_AACENC_PEAK_BITRATE, peak_bitrate))?;
+            }
+
+            check(sys::aacEncoder_SetParam(handle.ptr, sys::AACENC_PARAM_AACENC_SAMPLERATE, params.sample_rate))?;
+
+            if let Some(frame_length) = params.frame_length {
+                check(sys::aacEncoder_SetParam(handle.ptr, sys::AACENC_PARAM_AACENC_GRANULE_LENGTH, frame_length))?;
+            }
+
+            match check(sys::aacEncoder_SetParam(handle.ptr, sys::AACENC_PARAM_AACENC_TRANSMUX, params.transport.transmux() as u32)) {
+                Err(EncoderError::FdkAac(_)) if params.transport == Transport::Drm => {
+                    return Err(EncoderError::Unsupported("The linked libfdk-aac was built without DRM support."));
+                }
+                result => result?,
+            }
+
+            check(sys::aacEncoder_SetParam(handle.ptr, sys::AACENC_PARAM_AACENC_PROTECTION, params.protection as u32))?;
+
+            if let Some(audio_mux_version) = params.audio_mux_version {
+                check(sys::aacEncoder_SetParam(handle.ptr, sys::AACENC_PARAM_AACENC_AUDIOMUXVER, audio_mux_version))?;
+            }
+
+            if let Some(tp_subframes) = params.tp_subframes {
+                check(sys::aacEncoder_SetParam(handle.ptr, sys::AACENC_PARAM_AACENC_TPSUBFRAMES, tp_subframes))?;
+            }
+
+            if let Some(header_period) = params.header_period {
+                check(sys::aacEncoder_SetParam(handle.ptr, sys::AACENC_PARAM_AACENC_HEADER_PERIOD, header_period))?;
+            }
+
+            // HE-AAC and HE-AACv2 imply SBR (and PS for v2), so this mainly
+            // matters for ELD. Auto leaves the choice to the library:
+            match params.sbr {
+                SbrMode::Auto => {}
+                SbrMode::Off => check(sys::aacEncoder_SetParam(handle.ptr, sys::AACENC_PARAM_AACENC_SBR_MODE, 0))?,
+                SbrMode::On => check(sys::aacEncoder_SetParam(handle.ptr, sys::AACENC_PARAM_AACENC_SBR_MODE, 1))?,
+            }
+
+            if let Some(signaling_mode) = params.signaling_mode {
+                check(sys::aacEncoder_SetParam(handle.ptr, sys::AACENC_PARAM_AACENC_SIGNALING_MODE, match signaling_mode {
+                    SignalingMode::Implicit => 0,
+                    SignalingMode::ExplicitBackwardCompatible => 1,
+                    SignalingMode::ExplicitHierarchical => 2,
+                }))?;
+            }
+
+            if let Some(sbr_ratio) = params.sbr_ratio {
+                check(sys::aacEncoder_SetParam(handle.ptr, sys::AACENC_PARAM_AACENC_SBR_RATIO, match sbr_ratio {
+                    SbrRatio::Downsampled => 1,
+                    SbrRatio::DualRate => 2,
+                }))?;
+            }
+
+            check(sys::aacEncoder_SetParam(handle.ptr, sys::AACENC_PARAM_AACENC_CHANNELMODE, params.channels.mode() as u32))?;
+
+            check(sys::aacEncoder_SetParam(handle.ptr, sys::AACENC_PARAM_AACENC_CHANNELORDER, match params.channel_order {
+                ChannelOrder::Mpeg => 0,
+                ChannelOrder::Wav => 1,
+            }))?;
+
+            check(sys::aacEncoder_SetParam(handle.ptr, sys::AACENC_PARAM_AACENC_AFTERBURNER, params.afterburner as u32))?;
+
+            if let Some(ancillary_bitrate) = params.ancillary_bitrate {
+                check(sys::aacEncoder_SetParam(handle.ptr, sys::AACENC_PARAM_AACENC_ANCILLARY_BITRATE, ancillary_bitrate))?;
+            }
+
+            check(sys::aacEncoder_SetParam(handle.ptr, sys::AACENC_PARAM_AACENC_METADATA_MODE, match params.metadata_mode {
+                MetadataMode::None => 0,
+                MetadataMode::DynamicRange => 1,
+                MetadataMode::DynamicRangeAndAncillary => 2,
+                MetadataMode::Ancillary => 3,
+            }))?;
+
+            if let Some(bandwidth) = params.bandwidth {
+                check(sys::aacEncoder_SetParam(handle.ptr, sys::AACENC_PARAM_AACENC_BANDWIDTH, bandwidth))?;
+            }
+
+            // call encode once with all null params according to docs
+            handle.reconfigure()?;
+        }
+
+        let info = handle.info()?;
+
+        Ok(Encoder {
+            handle,
+            params,
+            info,
+            metadata: None,
+            ancillary: Vec::new(),
+            scratch: Vec::new(),
+            samples_encoded: 0,
+            frames_encoded: 0,
+            flushed: false,
+        })
+    }
+
+    pub fn with_preset(preset: Preset) -> Result<Self, EncoderError> {
+        Encoder::new(preset.params())
+    }
+
+    /// Wraps a handle from `aacEncOpen`, `params` is what `params()` and
+    /// `Debug` report for it.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be an opened and initialized encoder handle that is not
+    /// used or closed elsewhere, the Encoder closes it when dropped.
+    pub unsafe fn from_raw(ptr: sys::HANDLE_AACENCODER, params: EncoderParams) -> Result<Self, EncoderError> {
+        if ptr.is_null() {
+            return Err(EncoderError::FdkAac(sys::AACENC_ERROR_AACENC_INVALID_HANDLE));
+        }
+
+        let handle = EncoderHandle { ptr };
+        let info = handle.info()?;
+
+        Ok(Encoder {
+            handle,
+            params,
+            info,
+            metadata: None,
+            ancillary: Vec::new(),
+            scratch: Vec::new(),
+            samples_encoded: 0,
+            frames_encoded: 0,
+            flushed: false,
+        })
+    }
+
+    // Parameters set through the raw handle take effect on the next encode
+    // call, but are not reflected in params(), channels() and friends.
+    pub fn as_raw(&self) -> sys::HANDLE_AACENCODER {
+        self.handle.ptr
+    }
+
+    pub fn info(&self) -> Result<EncoderInfo, EncoderError> {
+        self.handle.info()
+    }
+
+    // Pass to Encoder::new to get an identically configured encoder.
+    pub fn params(&self) -> EncoderParams {
+        self.params.clone()
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.handle.get_param(sys::AACENC_PARAM_AACENC_SAMPLERATE)
+    }
+
+    pub fn channels(&self) -> usize {
+        self.info.input_channels
+    }
+
+    pub fn frame_length(&self) -> usize {
+        self.info.frame_length
+    }
+
+    pub fn max_output_bytes(&self) -> usize {
+        self.info.max_out_buf_bytes
+    }
+
+    pub fn set_metadata(&mut self, metadata: Option<MetaData>) {
+        self.metadata = metadata.map(|metadata| metadata.raw());
+    }
+
+    pub fn get_param(&self, param: sys::AACENC_PARAM) -> u32 {
+        self.handle.get_param(param)
+    }
+
+    pub fn bitrate(&self) -> u32 {
+        self.handle.get_param(sys::AACENC_PARAM_AACENC_BITRATE)
+    }
+
+    pub fn bandwidth(&self) -> u32 {
+        self.handle.get_param(sys::AACENC_PARAM_AACENC_BANDWIDTH)
+    }
+
+    pub fn sbr_enabled(&self) -> bool {
+        self.handle.get_param(sys::AACENC_PARAM_AACENC_SBR_MODE) != 0
+    }
+
+    // Checked like the bitrate given to new: the range for CBR, and for VBR
+    // whether the audio object type supports it.
+    pub fn set_bitrate(&mut self, bit_rate: BitRate) -> Result<(), EncoderError> {
+        validate(&EncoderParams { bit_rate, ..self.params.clone() })?;
+
+        self.handle.set_bitrate(bit_rate)?;
+        self.handle.reconfigure()?;
+        self.info = self.handle.info()?;
+        self.params.bit_rate = bit_rate;
+        Ok(())
+    }
+
+    pub fn set_bandwidth(&mut self, bandwidth: u32) -> Result<(), EncoderError> {
+        self.handle.set_param(sys::AACENC_PARAM_AACENC_BANDWIDTH, bandwidth)?;
+        self.handle.reconfigure()?;
+        self.info = self.handle.info()?;
+        self.params.bandwidth = Some(bandwidth);
+        Ok(())
+    }
+
+    // Queued bytes are embedded into the following frames as the ancillary
+    // bitrate and frame size allow.
+    pub fn queue_ancillary_data(&mut self, data: &[u8]) {
+        self.ancillary.extend_from_slice(data);
+    }
+
+    // Clears all internal state so the next track can be encoded with the
+    // same configuration, also needed after flush.
+    pub fn reset(&mut self) -> Result<(), EncoderError> {
+        self.handle.set_param(sys::AACENC_PARAM_AACENC_CONTROL_STATE, sys::AACENC_CTRLFLAGS_AACENC_INIT_ALL)?;
+        self.handle.reconfigure()?;
+        self.info = self.handle.info()?;
+
+        self.ancillary.clear();
+        self.samples_encoded = 0;
+        self.frames_encoded = 0;
+        self.flushed = false;
+        Ok(())
+    }
+
+    pub fn delay(&self) -> Result<usize, EncoderError> {
+        Ok(self.info()?.delay)
+    }
+
+    // Only complete once the encoder has been flushed.
+    pub fn gapless_info(&self) -> Result<GaplessInfo, EncoderError> {
+        let info = self.info()?;
+
+        let total = self.frames_encoded * info.frame_length as u64;
+        let priming = info.delay as u64;
+
+        Ok(GaplessInfo {
+            priming: info.delay,
+            padding: total.saturating_sub(priming + self.samples_encoded) as usize,
+            samples: self.samples_encoded,
+        })
+    }
+
+    pub fn encode_frame<S: Sample>(&mut self, pcm: &[S], out: &mut [u8]) -> Result<EncodeInfo, EncoderError> {
+        if let Some(pcm) = S::as_i16(pcm) {
+            return self.encode_samples(pcm, pcm.len() as c_int, out);
+        }
+
+        let mut scratch = mem::take(&mut self.scratch);
+        scratch.clear();
+        scratch.extend(pcm.iter().map(|sample| sample.to_i16()));
+
+        let result = self.encode_samples(&scratch, scratch.len() as c_int, out);
+        self.scratch = scratch;
+        result
+    }
+
+    pub fn encode_planar<S: Sample>(&mut self, pcm: &[&[S]], out: &mut [u8]) -> Result<EncodeInfo, EncoderError> {
+        let channels = self.info.input_channels;
+        let frames = pcm.first().map(|channel| channel.len()).unwrap_or(0);
+        if pcm.len() != channels || pcm.iter().any(|channel| channel.len() != frames) {
+            return Err(EncoderError::InvalidParams("Planar input needs one buffer of equal length per channel."));
+        }
+
+        let mut scratch = mem::take(&mut self.scratch);
+        scratch.clear();
+        for frame in 0..frames {
+            scratch.extend(pcm.iter().map(|channel| channel[frame].to_i16()));
+        }
+
+        let result = self.encode_samples(&scratch, scratch.len() as c_int, out);
+        self.scratch = scratch;
+        result
+    }
+
+    // num_samples of -1 signals end of stream, any other input after a flush
+    // starts a new one.
+    fn encode_samples(&mut self, pcm: &[i16], num_samples: c_int, out: &mut [u8]) -> Result<EncodeInfo, EncoderError> {
+        if self.flushed && num_samples >= 0 {
+            // ancillary data queued since belongs to the new stream
+            let ancillary = mem::take(&mut self.ancillary);
+            self.reset()?;
+            self.ancillary = ancillary;
+        }
+
+        let mut metadata = self.metadata;
+        let anc_len = self.ancillary.len().min(self.info.max_anc_bytes);
+
+        let mut input_desc = BufDesc::new();
+        input_desc.push(sys::AACENC_BufferIdentifier_IN_AUDIO_DATA,
+            pcm.as_ptr() as *mut c_void, mem::size_of_val(pcm), mem::size_of::<i16>());
+        if anc_len > 0 {
+            input_desc.push(sys::AACENC_BufferIdentifier_IN_ANCILLRY_DATA,
+                self.ancillary.as_mut_ptr() as *mut c_void, anc_len, mem::size_of::<u8>());
+        }
+        if let Some(metadata) = metadata.as_mut() {
+            input_desc.push(sys::AACENC_BufferIdentifier_IN_METADATA_SETUP,
+                metadata as *mut _ as *mut c_void,
+                mem::size_of::<sys::AACENC_MetaData>(), mem::size_of::<sys::AACENC_MetaData>());
+        }
+
+        let mut output_desc = BufDesc::new();
+        output_desc.push(sys::AACENC_BufferIdentifier_OUT_BITSTREAM_DATA,
+            out.as_mut_ptr() as *mut c_void, out.len(), mem::size_of::<u8>());
+
+        let in_args = sys::AACENC_InArgs {
+            numInSamples: num_samples,
+            numAncBytes: anc_len as c_int,
+        };
+
+        let mut out_args: sys::AACENC_OutArgs = unsafe { mem::zeroed() };
+
+        check(unsafe {
+            sys::aacEncEncode(
+                self.handle.ptr,
+                &input_desc.as_sys(),
+                &output_desc.as_sys(),
+                &in_args,
+                &mut out_args,
+            )
+        })?;
+
+        self.ancillary.drain(0..out_args.numAncBytes as usize);
+
+        self.samples_encoded += out_args.numInSamples as u64 / self.info.input_channels as u64;
+        if out_args.numOutBytes > 0 {
+            self.frames_encoded += 1;
+        }
+
+        Ok(EncodeInfo {
+            input_consumed: out_args.numInSamples as usize,
+            output_size: out_args.numOutBytes as usize,
+        })
+    }
+
+    // Drains the frames still held in the encoder delay line at end of
+    // stream. Further input is encoded as a new stream, the encoder is reset
+    // on the next encode_frame or encode_planar, so gapless_info() still
+    // describes this one until then.
+    pub fn flush<W: Write>(&mut self, output: &mut W) -> Result<EncodeInfo, EncoderError> {
+        let mut output_buffer = vec![0; self.info.max_out_buf_bytes];
+
+        let mut total_written_bytes = 0;
+        loop {
+            let frame = match self.encode_samples(&[], -1, &mut output_buffer) {
+                Err(EncoderError::FdkAac(sys::AACENC_ERROR_AACENC_ENCODE_EOF)) => break,
+                result => result?,
+            };
+
+            if frame.output_size == 0 {
+                break;
+            }
+
+            output.write_all(&output_buffer[0..frame.output_size])?;
+            total_written_bytes += frame.output_size;
+        }
+
+        self.flushed = true;
+        Ok(EncodeInfo {
+            output_size: total_written_bytes,
+            input_consumed: 0,
+        })
+    }
+
+    // Encodes pcm as one complete stream, flushed at the end, so another
+    // call starts a new stream from a reset encoder.
+    pub fn encode_to_vec<S: Sample>(&mut self, pcm: &[S]) -> Result<Vec<u8>, EncoderError> {
+        let mut output_buffer = vec![0; self.info.max_out_buf_bytes];
+        let mut output = Vec::new();
+
+        let mut offset = 0;
+        while offset < pcm.len() {
+            let frame = self.encode_frame(&pcm[offset..], &mut output_buffer)?;
+            output.extend_from_slice(&output_buffer[0..frame.output_size]);
+            offset += frame.input_consumed;
+        }
+
+        self.flush(&mut output)?;
+        Ok(output)
+    }
+
+    pub fn frames<R: Read>(&mut self, input: R) -> Frames<'_, R> {
+        Frames {
+            input_buffer: PcmInput::new(self.info.input_channels, self.info.frame_length),
+            output_buffer: vec![0; self.info.max_out_buf_bytes],
+            encoder: self,
+            input,
+            flushing: false,
+            done: false,
+        }
+    }
+
+    pub fn encode<R: Read, W: Write>(&mut self, input: &mut R, output: &mut W) -> Result<EncodeInfo, EncoderError> {
+        let info = self.info()?;
+
+        let mut input_buffer = PcmInput::new(info.input_channels, info.frame_length);
+        let mut output_buffer = vec![0; info.max_out_buf_bytes];
+
+        let mut total_consumed_samples = 0;
+        let mut total_written_bytes = 0;
+        loop {
+            let eof = !input_buffer.read(input)?;
+
+            let samples = input_buffer.samples();
+            if samples.is_empty() {
+                if eof {
+                    break;
+                }
+                continue;
+            }
+
+            let frame = self.encode_frame(samples, &mut output_buffer)?;
+            input_buffer.consume(frame.input_consumed);
+
+            output.write_all(&output_buffer[0..frame.output_size])?;
+            total_consumed_samples += frame.input_consumed;
+            total_written_bytes += frame.output_size;
+
+            if eof && frame.input_consumed == 0 && frame.output_size == 0 {
+                break;
+            }
+        }
+
+        let flushed = self.flush(output)?;
+        total_consumed_samples += flushed.input_consumed;
+        total_written_bytes += flushed.output_size;
+
+        Ok(EncodeInfo {
+            output_size: total_written_bytes,
+            input_consumed: total_consumed_samples,
+        })
+    }
+}
+
+// Interleaved native endian 16 bit PCM read in chunks of a frame. Input the
+// encoder didn't take, including a partial sample from a short read, is
+// kept for the next call.
+#[cfg(feature = "native")]
+struct PcmInput {
+    pending: Vec<u8>,
+    samples: Vec<i16>,
+    frame_bytes: usize,
+    chunk_size: usize,
+}
+
+#[cfg(feature = "native")]
+impl PcmInput {
+    fn new(channels: usize, frame_length: usize) -> Self {
+        let frame_bytes = channels * mem::size_of::<i16>();
+
+        PcmInput {
+            pending: Vec::new(),
+            samples: Vec::new(),
+            frame_bytes,
+            chunk_size: frame_bytes * frame_length,
+        }
+    }
+
+    // Appends one read, false at the end of the input.
+    fn read<R: Read>(&mut self, input: &mut R) -> io::Result<bool> {
+        let len = self.pending.len();
+        self.pending.resize(len + self.chunk_size, 0);
+
+        let result = input.read(&mut self.pending[len..]);
+        self.pending.truncate(len + *result.as_ref().unwrap_or(&0));
+
+        Ok(result? > 0)
+    }
+
+    // The whole sample frames across all channels read so far.
+    fn samples(&mut self) -> &[i16] {
+        let len = self.pending.len() / self.frame_bytes * self.frame_bytes;
+
+        self.samples.clear();
+        self.samples.extend(self.pending[0..len].chunks(2).map(|bytes| i16::from_ne_bytes([bytes[0], bytes[1]])));
+        &self.samples
+    }
+
+    fn consume(&mut self, samples: usize) {
+        self.pending.drain(0..samples * mem::size_of::<i16>());
+    }
+}
+
+// Object safe subset of the Encoder API, so it can be swapped for a fake
+// in tests or for another backend.
+pub trait AacEncode {
+    fn encode_frame(&mut self, pcm: &[i16], out: &mut [u8]) -> Result<EncodeInfo, EncoderError>;
+    fn flush(&mut self, output: &mut dyn Write) -> Result<EncodeInfo, EncoderError>;
+    fn info(&self) -> Result<EncoderInfo, EncoderError>;
+}
+
+#[cfg(feature = "native")]
+impl AacEncode for Encoder {
+    fn encode_frame(&mut self, pcm: &[i16], out: &mut [u8]) -> Result<EncodeInfo, EncoderError> {
+        Encoder::encode_frame(self, pcm, out)
+    }
+
+    fn flush(&mut self, mut output: &mut dyn Write) -> Result<EncodeInfo, EncoderError> {
+        Encoder::flush(self, &mut output)
+    }
+
+    fn info(&self) -> Result<EncoderInfo, EncoderError> {
+        Encoder::info(self)
+    }
+}
+
+// PCM sample formats accepted by the encoder, converted to the 16 bit
+// samples libfdk-aac works with.
+pub trait Sample: Copy {
+    fn to_i16(self) -> i16;
+
+    fn as_i16(_samples: &[Self]) -> Option<&[i16]> {
+        None
+    }
+}
+
+impl Sample for i16 {
+    fn to_i16(self) -> i16 {
+        self
+    }
+
+    fn as_i16(samples: &[i16]) -> Option<&[i16]> {
+        Some(samples)
+    }
+}
+
+impl Sample for i32 {
+    fn to_i16(self) -> i16 {
+        (self >> 16) as i16
+    }
+}
+
+// Samples are expected in -1.0..=1.0, anything outside is clipped.
+impl Sample for f32 {
+    fn to_i16(self) -> i16 {
+        (self * 32768.0).round().clamp(-32768.0, 32767.0) as i16
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EncodedFrame {
+    pub data: Vec<u8>,
+    pub input_consumed: usize,
+}
+
+#[cfg(feature = "native")]
+pub struct Frames<'a, R> {
+    encoder: &'a mut Encoder,
+    input: R,
+    input_buffer: PcmInput,
+    output_buffer: Vec<u8>,
+    flushing: bool,
+    done: bool,
+}
+
+#[cfg(feature = "native")]
+impl<'a, R: Read> Frames<'a, R> {
+    fn next_frame(&mut self) -> Result<Option<EncodedFrame>, EncoderError> {
+        // the encoder buffers input until a full frame is available, so
+        // keep feeding it until it produces output
+        let mut input_consumed = 0;
+        loop {
+            let frame = if self.flushing {
+                match self.encoder.encode_samples(&[], -1, &mut self.output_buffer) {
+                    Err(EncoderError::FdkAac(sys::AACENC_ERROR_AACENC_ENCODE_EOF)) => {
+                        self.encoder.flushed = true;
+                        return Ok(None);
+                    }
+                    result => result?,
+                }
+            } else {
+                let eof = !self.input_buffer.read(&mut self.input)?;
+
+                let samples = self.input_buffer.samples();
+                if samples.is_empty() {
+                    self.flushing = eof;
+                    continue;
+                }
+
+                let frame = self.encoder.encode_frame(samples, &mut self.output_buffer)?;
+                self.input_buffer.consume(frame.input_consumed);
+                if eof && frame.input_consumed == 0 && frame.output_size == 0 {
+                    self.flushing = true;
+                    continue;
+                }
+                frame
+            };
+
+            input_consumed += frame.input_consumed;
+
+            if frame.output_size > 0 {
+                return Ok(Some(EncodedFrame {
+                    data: self.output_buffer[0..frame.output_size].to_vec(),
+                    input_consumed,
+                }));
+            }
+
+            if self.flushing {
+                self.encoder.flushed = true;
+                return Ok(None);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "native")]
+impl<'a, R: Read> Iterator for Frames<'a, R> {
+    type Item = Result<EncodedFrame, EncoderError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.next_frame() {
+            Ok(Some(frame)) => Some(Ok(frame)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+// Accepts interleaved native endian 16 bit PCM of any size and writes the
+// encoded stream to the inner writer. Flushing only flushes the inner
+// writer, the encoder delay line is drained by finish() or on drop.
+#[cfg(feature = "native")]
+pub struct AacWriter<W: Write> {
+    encoder: Encoder,
+    inner: Option<W>,
+    pending: Vec<u8>,
+    samples: Vec<i16>,
+    output_buffer: Vec<u8>,
+    channels: usize,
+}
+
+#[cfg(feature = "native")]
+impl<W: Write> AacWriter<W> {
+    pub fn new(encoder: Encoder, inner: W) -> Result<Self, EncoderError> {
+        let info = encoder.info()?;
+
+        Ok(AacWriter {
+            encoder,
+            inner: Some(inner),
+            pending: Vec::new(),
+            samples: Vec::new(),
+            output_buffer: vec![0; info.max_out_buf_bytes],
+            channels: info.input_channels,
+        })
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.inner.as_ref().unwrap()
+    }
+
+    pub fn encoder(&self) -> &Encoder {
+        &self.encoder
+    }
+
+    fn encode_pending(&mut self) -> Result<(), EncoderError> {
+        // the encoder wants whole sample frames across all channels
+        let frame_bytes = self.channels * mem::size_of::<i16>();
+        let len = self.pending.len() / frame_bytes * frame_bytes;
+
+        self.samples.clear();
+        self.samples.extend(self.pending[0..len].chunks(2).map(|bytes| i16::from_ne_bytes([bytes[0], bytes[1]])));
+        self.pending.drain(0..len);
+
+        let inner = self.inner.as_mut().unwrap();
+        let mut offset = 0;
+        while offset < self.samples.len() {
+            let frame = self.encoder.encode_frame(&self.samples[offset..], &mut self.output_buffer)?;
+            inner.write_all(&self.output_buffer[0..frame.output_size])?;
+            offset += frame.input_consumed;
+        }
+
+        Ok(())
+    }
+
+    fn drain(&mut self) -> Result<(), EncoderError> {
+        self.encode_pending()?;
+        let inner = self.inner.as_mut().unwrap();
+        self.encoder.flush(inner)?;
+        inner.flush()?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W, EncoderError> {
+        self.drain()?;
+        Ok(self.inner.take().unwrap())
+    }
+}
+
+#[cfg(feature = "native")]
+fn io_error(err: EncoderError) -> io::Error {
+    match err {
+        EncoderError::Io(err) => err,
+        err => io::Error::other(err),
+    }
+}
+
+#[cfg(feature = "native")]
+impl<W: Write> Write for AacWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        self.encode_pending().map_err(io_error)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.as_mut().unwrap().flush()
+    }
+}
+
+#[cfg(feature = "native")]
+impl<W: Write> Drop for AacWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.drain();
+        }
+    }
+}
+
+// libfdk-aac keeps all encoder state behind the handle, with no thread
+// locals or thread affinity, so the handle can move between threads as long
+// as it is only used from one at a time, which &mut self guarantees.
+#[cfg(feature = "native")]
+unsafe impl Send for Encoder {}
+
+#[cfg(feature = "native")]
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+
+    assert_send::<Encoder>();
+    assert_send::<AacWriter<Vec<u8>>>();
+};
+
+#[cfg(feature = "native")]
+impl Debug for Encoder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Encoder")
+            .field("audio_object_type", &self.params.audio_object_type)
+            .field("bit_rate", &self.params.bit_rate)
+            .field("sample_rate", &self.params.sample_rate)
+            .field("channels", &self.params.channels)
+            .field("transport", &self.params.transport)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_params() {
+        let params: EncoderParams = "he_aac_v2, cbr:48k, 48000, stereo, loas".parse().unwrap();
+        assert_eq!(params.audio_object_type, AudioObjectType::HeAacV2);
+        assert!(matches!(params.bit_rate, BitRate::Cbr(48000)));
+        assert_eq!(params.sample_rate, 48000);
+        assert_eq!(params.channels, ChannelMode::Stereo);
+        assert_eq!(params.transport, Transport::Loas);
+
+        let params: EncoderParams = "LC,vbr:high,5.1".parse().unwrap();
+        assert_eq!(params.audio_object_type, AudioObjectType::AacLc);
+        assert!(matches!(params.bit_rate, BitRate::VbrHigh));
+        assert_eq!(params.channels, ChannelMode::FivePointOne);
+        // the rest is left at the default
+        assert_eq!(params.sample_rate, 44100);
+        assert_eq!(params.transport, Transport::Adts);
+    }
+
+    #[test]
+    fn parse_params_errors() {
+        assert_eq!("".parse::<EncoderParams>().unwrap_err(), ParseParamsError::Empty);
+        assert_eq!("lc,opus".parse::<EncoderParams>().unwrap_err(), ParseParamsError::UnknownToken("opus".to_owned()));
+        assert_eq!("lc,".parse::<EncoderParams>().unwrap_err(), ParseParamsError::UnknownToken("".to_owned()));
+
+        for token in ["cbr:", "cbr:fast", "cbr:99999999k", "vbr:6", "vbr:medium_high"] {
+            assert_eq!(format!("lc,{}", token).parse::<EncoderParams>().unwrap_err(), ParseParamsError::InvalidBitRate(token.to_owned()));
+        }
+
+        assert_eq!("lc,he".parse::<EncoderParams>().unwrap_err(), ParseParamsError::Duplicate("audio object type", "he".to_owned()));
+        assert_eq!("cbr:64k,vbr:3".parse::<EncoderParams>().unwrap_err(), ParseParamsError::Duplicate("bitrate", "vbr:3".to_owned()));
+        assert_eq!("44100,Stereo,48000".parse::<EncoderParams>().unwrap_err(), ParseParamsError::Duplicate("sample rate", "48000".to_owned()));
+
+        // parsed fine but rejected by validate
+        assert!(matches!("he_aac_v2,mono".parse::<EncoderParams>(), Err(ParseParamsError::Invalid(_))));
+        assert!(matches!("lc,1234".parse::<EncoderParams>(), Err(ParseParamsError::Invalid(_))));
+    }
+
+    fn invalid(builder: EncoderParamsBuilder) -> &'static str {
+        match builder.build() {
+            Err(EncoderError::InvalidParams(msg)) => msg,
+            other => panic!("expected InvalidParams, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn builder_defaults_and_presets_validate() {
+        EncoderParams::builder().build().unwrap();
+
+        for preset in [Preset::VoiceMono16k, Preset::MusicStereo44k, Preset::MusicLowBitrate,
+            Preset::BroadcastStereo48k, Preset::LowLatencyStereo48k] {
+            validate(&preset.params()).unwrap();
+        }
+    }
+
+    #[test]
+    fn he_aac_v2_requires_stereo() {
+        let builder = EncoderParams::builder().audio_object_type(AudioObjectType::HeAacV2).bit_rate(BitRate::Cbr(32000));
+        builder.clone().build().unwrap();
+        assert_eq!(invalid(builder.channels(ChannelMode::Mono)), "HE-AACv2 requires stereo channel mode.");
+    }
+
+    #[test]
+    fn frame_length() {
+        for aot in [AudioObjectType::AacLd, AudioObjectType::AacEld] {
+            let builder = EncoderParams::builder().audio_object_type(aot).sample_rate(48000).bit_rate(BitRate::Cbr(96000));
+            builder.clone().frame_length(512).build().unwrap();
+            builder.clone().frame_length(480).build().unwrap();
+            assert_eq!(invalid(builder.frame_length(1024)), "AAC-LD and AAC-ELD require a frame length of 512 or 480 samples.");
+        }
+
+        let builder = EncoderParams::builder();
+        builder.clone().frame_length(960).build().unwrap();
+        assert_eq!(invalid(builder.frame_length(480)), "Frame length must be 1024 or 960 samples.");
+    }
+
+    #[test]
+    fn sample_rate_and_bitrate_ranges() {
+        let builder = EncoderParams::builder();
+        assert_eq!(invalid(builder.clone().sample_rate(44000)), "Sample rate is not supported by the audio object type.");
+        assert_eq!(invalid(builder.clone().bit_rate(BitRate::Cbr(7999))), "Bitrate is out of range for the audio object type, sample rate and channel mode.");
+        assert_eq!(invalid(builder.clone().bit_rate(BitRate::Cbr(6 * 44100 * 2 + 1))), "Bitrate is out of range for the audio object type, sample rate and channel mode.");
+        // VBR has no fixed rate to check
+        builder.clone().bit_rate(BitRate::VbrVeryHigh).build().unwrap();
+
+        assert_eq!(invalid(builder.clone().audio_object_type(AudioObjectType::HeAac).sample_rate(96000)),
+            "Sample rate is not supported by the audio object type.");
+        assert_eq!(invalid(builder.audio_object_type(AudioObjectType::AacEld).sbr(SbrMode::On).sample_rate(8000).bit_rate(BitRate::Cbr(24000))),
+            "AAC-ELD with SBR requires a sample rate of 16 to 48 kHz.");
+    }
+
+    #[test]
+    fn bitrate_limits() {
+        assert_eq!(bitrate_range(AudioObjectType::AacLc, ChannelMode::Stereo, 48000), Some((8000, 576000)));
+        assert_eq!(bitrate_range(AudioObjectType::HeAac, ChannelMode::Stereo, 16000), Some((8000, 96000)));
+        assert_eq!(bitrate_range(AudioObjectType::HeAac, ChannelMode::Stereo, 48000), Some((8000, 128000)));
+        assert_eq!(bitrate_range(AudioObjectType::HeAacV2, ChannelMode::Stereo, 16000), Some((8000, 48000)));
+        assert_eq!(bitrate_range(AudioObjectType::HeAacV2, ChannelMode::Stereo, 48000), Some((8000, 64000)));
+        assert_eq!(bitrate_range(AudioObjectType::AacLd, ChannelMode::Mono, 96000), Some((8000, 576000)));
+        assert_eq!(bitrate_range(AudioObjectType::AacEld, ChannelMode::Mono, 96000), None);
+    }
+
+    #[test]
+    fn usac_and_mps_restrictions() {
+        let usac = EncoderParams::builder().audio_object_type(AudioObjectType::Usac).bit_rate(BitRate::Cbr(64000));
+        usac.clone().build().unwrap();
+        assert_eq!(invalid(usac.clone().channels(ChannelMode::FivePointOne)), "xHE-AAC only supports mono or stereo.");
+        assert_eq!(invalid(usac.bit_rate(BitRate::VbrMedium)), "xHE-AAC only supports constant bitrate encoding.");
+
+        assert_eq!(invalid(EncoderParams::builder().channels(ChannelMode::TwoOneTwo)), "MPEG Surround 212 is only supported with AAC-ELD.");
+    }
+
+    #[test]
+    fn latm_options() {
+        let loas = EncoderParams::builder().transport(Transport::Loas);
+        loas.clone().audio_mux_version(1).tp_subframes(2).build().unwrap();
+        assert_eq!(invalid(loas.audio_mux_version(3)), "AudioMuxVersion must be 0, 1 or 2.");
+        assert_eq!(invalid(EncoderParams::builder().tp_subframes(2)),
+            "AudioMuxVersion and TP subframes are only supported with LATM/LOAS transports.");
+    }
+
+    #[test]
+    fn dab_plus_and_drm() {
+        let dab = EncoderParams::builder().transport(Transport::DabPlus).sample_rate(48000).frame_length(960);
+        dab.clone().build().unwrap();
+        assert_eq!(invalid(dab.clone().frame_length(1024)), "DAB+ requires a frame length of 960 samples.");
+        assert_eq!(invalid(dab.clone().sample_rate(44100)), "DAB+ requires a sample rate of 32 or 48 kHz.");
+        assert_eq!(invalid(dab.clone().channels(ChannelMode::FivePointOne)), "DAB+ only supports mono or stereo.");
+        assert_eq!(invalid(dab.audio_object_type(AudioObjectType::HeAac).bit_rate(BitRate::Cbr(64000)).sbr_ratio(SbrRatio::Downsampled)),
+            "DAB+ requires dual-rate SBR.");
+
+        let drm = EncoderParams::builder().transport(Transport::Drm).sample_rate(48000).frame_length(960);
+        drm.clone().build().unwrap();
+        assert_eq!(invalid(drm.clone().frame_length(1024)), "DRM requires a frame length of 960 samples for AAC.");
+        assert_eq!(invalid(drm.audio_object_type(AudioObjectType::AacLd).frame_length(480)), "DRM only supports AAC-LC, HE-AAC, HE-AACv2 and xHE-AAC.");
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn encode_planar_after_flush() {
+        let mut encoder = Encoder::new(EncoderParams::default()).unwrap();
+        let info = encoder.info().unwrap();
+        let mut out = vec![0; info.max_out_buf_bytes];
+
+        let interleaved = vec![0i16; info.frame_length * 2];
+        encoder.encode_frame(&interleaved, &mut out).unwrap();
+        encoder.flush(&mut Vec::new()).unwrap();
+        assert_eq!(encoder.gapless_info().unwrap().samples, info.frame_length as u64);
+
+        let left = vec![0i16; info.frame_length];
+        let right = vec![0i16; info.frame_length];
+        let frame = encoder.encode_planar(&[&left[..], &right[..]], &mut out).unwrap();
+        assert_eq!(frame.input_consumed, info.frame_length * 2);
+        // counted as the start of a new stream
+        assert_eq!(encoder.gapless_info().unwrap().samples, info.frame_length as u64);
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn set_bitrate_is_validated() {
+        let mut encoder = Encoder::new(EncoderParams::default()).unwrap();
+
+        assert!(matches!(encoder.set_bitrate(BitRate::Cbr(1_000_000)), Err(EncoderError::InvalidParams(_))));
+        assert!(matches!(encoder.set_bitrate(BitRate::Cbr(1000)), Err(EncoderError::InvalidParams(_))));
+        assert_eq!(encoder.bitrate(), 128000);
+
+        encoder.set_bitrate(BitRate::Cbr(96000)).unwrap();
+        assert_eq!(encoder.bitrate(), 96000);
+        encoder.set_bitrate(BitRate::VbrHigh).unwrap();
     }
 }
@@ -0,0 +1,247 @@
+use std::io::{self, Read};
+
+const READ_CHUNK_SIZE: usize = 4096;
+
+// Splits a byte stream into frames starting with a syncword, shared by
+// AdtsFrames and LoasFrames. A leading ID3v2 tag is skipped. After a broken
+// header it searches for the next syncword, only accepting one that is
+// followed by another header a frame length later.
+pub(crate) struct FrameReader<R> {
+    input: R,
+    buffer: Vec<u8>,
+    pos: usize,
+    searching: bool,
+    skipped_bytes: u64,
+    started: bool,
+    eof: bool,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub(crate) fn new(input: R) -> Self {
+        FrameReader {
+            input,
+            buffer: Vec::new(),
+            pos: 0,
+            searching: false,
+            skipped_bytes: 0,
+            started: false,
+            eof: false,
+        }
+    }
+
+    pub(crate) fn skipped_bytes(&self) -> u64 {
+        self.skipped_bytes
+    }
+
+    pub(crate) fn into_inner(self) -> R {
+        self.input
+    }
+
+    // frame_length parses the header at the start of its argument, which
+    // holds at least header_size bytes, and returns the length of the frame
+    // including the header.
+    pub(crate) fn next_frame(&mut self, header_size: usize, frame_length: impl Fn(&[u8]) -> Option<usize>) -> io::Result<Option<Vec<u8>>> {
+        if !self.started {
+            self.started = true;
+            self.skip_id3_tag()?;
+        }
+
+        loop {
+            if !self.fill(header_size)? {
+                break;
+            }
+
+            let len = match frame_length(&self.buffer[self.pos..]) {
+                Some(len) => len,
+                None => {
+                    self.searching = true;
+                    self.skip_byte();
+                    continue;
+                }
+            };
+
+            if self.searching {
+                self.fill(len + header_size)?;
+                let next = &self.buffer[self.pos + len.min(self.buffer.len() - self.pos)..];
+                // a header cut off by the end of the input is given the
+                // benefit of the doubt
+                if next.len() >= header_size && frame_length(next).is_none() {
+                    self.skip_byte();
+                    continue;
+                }
+            }
+
+            if !self.fill(len)? {
+                break;
+            }
+
+            self.searching = false;
+            let data = self.buffer[self.pos..self.pos + len].to_vec();
+            self.pos += len;
+            return Ok(Some(data));
+        }
+
+        // whatever is left can't be a complete frame
+        self.skipped_bytes += (self.buffer.len() - self.pos) as u64;
+        self.pos = self.buffer.len();
+        Ok(None)
+    }
+
+    fn skip_byte(&mut self) {
+        self.pos += 1;
+        self.skipped_bytes += 1;
+    }
+
+    // Reads until at least len unconsumed bytes are buffered, false if the
+    // input ends first.
+    fn fill(&mut self, len: usize) -> io::Result<bool> {
+        if self.pos > 0 && self.pos >= self.buffer.len() / 2 {
+            self.buffer.drain(..self.pos);
+            self.pos = 0;
+        }
+
+        while self.buffer.len() - self.pos < len && !self.eof {
+            let start = self.buffer.len();
+            self.buffer.resize(start + READ_CHUNK_SIZE, 0);
+
+            let read = loop {
+                match self.input.read(&mut self.buffer[start..]) {
+                    Ok(read) => break read,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        self.buffer.truncate(start);
+                        return Err(e);
+                    }
+                }
+            };
+
+            self.buffer.truncate(start + read);
+            self.eof = read == 0;
+        }
+
+        Ok(self.buffer.len() - self.pos >= len)
+    }
+
+    // The tag can hold megabytes of artwork, what isn't buffered yet is
+    // discarded as it is read.
+    fn skip_id3_tag(&mut self) -> io::Result<()> {
+        self.fill(ID3_HEADER_SIZE)?;
+        if let Some(size) = id3_tag_size(&self.buffer[self.pos..]) {
+            let buffered = self.buffer.len() - self.pos;
+            if size <= buffered {
+                self.pos += size;
+            } else {
+                self.pos = self.buffer.len();
+                io::copy(&mut (&mut self.input).take((size - buffered) as u64), &mut io::sink())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) const ID3_HEADER_SIZE: usize = 10;
+
+// Total size of the ID3v2 tag starting with header, including the header
+// itself and the footer if present.
+pub(crate) fn id3_tag_size(header: &[u8]) -> Option<usize> {
+    if header.len() < ID3_HEADER_SIZE || &header[0..3] != b"ID3" {
+        return None;
+    }
+
+    // the size is stored as four 7 bit bytes
+    let size_bytes = &header[6..10];
+    if size_bytes.iter().any(|byte| byte & 0x80 != 0) {
+        return None;
+    }
+    let size = size_bytes.iter().fold(0, |size, byte| (size << 7) | *byte as usize);
+
+    let footer = if header[5] & 0x10 != 0 { ID3_HEADER_SIZE } else { 0 };
+    Some(ID3_HEADER_SIZE + size + footer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a syncword byte followed by the frame length, header included
+    fn frame_length(data: &[u8]) -> Option<usize> {
+        match data {
+            [0xaa, len, ..] if *len >= 2 => Some(*len as usize),
+            _ => None,
+        }
+    }
+
+    fn frame(fill: u8, len: usize) -> Vec<u8> {
+        let mut frame = vec![0xaa, len as u8];
+        frame.resize(len, fill);
+        frame
+    }
+
+    fn read_all(data: &[u8]) -> (Vec<Vec<u8>>, u64) {
+        let mut reader = FrameReader::new(data);
+        let mut frames = Vec::new();
+        while let Some(frame) = reader.next_frame(2, frame_length).unwrap() {
+            frames.push(frame);
+        }
+        (frames, reader.skipped_bytes())
+    }
+
+    #[test]
+    fn consecutive_frames() {
+        let data = [frame(1, 10), frame(2, 20), frame(3, 30)].concat();
+        assert_eq!(read_all(&data), (vec![frame(1, 10), frame(2, 20), frame(3, 30)], 0));
+    }
+
+    #[test]
+    fn resync_after_junk() {
+        // the 0xaa in the junk looks like a header but isn't followed by one
+        let junk = [0x00, 0xaa, 0x05, 0x01, 0x02, 0x03, 0x04];
+        let data = [&junk[..], &frame(1, 10), &frame(2, 20), &junk, &frame(3, 30), &frame(4, 40)].concat();
+        assert_eq!(read_all(&data), (vec![frame(1, 10), frame(2, 20), frame(3, 30), frame(4, 40)], 2 * junk.len() as u64));
+    }
+
+    #[test]
+    fn resync_needs_a_following_header() {
+        // found while searching but followed by junk, so dropped as well
+        let data = [&[0x00][..], &frame(1, 10), &[0x00], &frame(2, 20), &frame(3, 30)].concat();
+        assert_eq!(read_all(&data), (vec![frame(2, 20), frame(3, 30)], 12));
+    }
+
+    #[test]
+    fn truncated_last_frame() {
+        let mut data = [frame(1, 10), frame(2, 20)].concat();
+        data.truncate(data.len() - 5);
+        assert_eq!(read_all(&data), (vec![frame(1, 10)], 15));
+    }
+
+    #[test]
+    fn skips_id3_tag() {
+        let mut data = b"ID3\x04\x00\x00\x00\x00\x00\x05".to_vec();
+        data.extend_from_slice(&[0xaa; 5]);
+        data.extend(frame(1, 10));
+
+        assert_eq!(id3_tag_size(&data), Some(ID3_HEADER_SIZE + 5));
+        assert_eq!(read_all(&data), (vec![frame(1, 10)], 0));
+    }
+
+    #[test]
+    fn skips_id3_tag_larger_than_a_read() {
+        let size = 3 * READ_CHUNK_SIZE;
+        let mut data = b"ID3\x04\x00\x00".to_vec();
+        data.extend((0..4).rev().map(|shift| (size >> (7 * shift)) as u8 & 0x7f));
+        data.resize(ID3_HEADER_SIZE + size, 0xaa);
+        data.extend(frame(1, 10));
+        data.extend(frame(2, 20));
+
+        assert_eq!(read_all(&data), (vec![frame(1, 10), frame(2, 20)], 0));
+    }
+
+    #[test]
+    fn id3_tag_size_with_footer() {
+        // 0x81 = 1 << 7 | 1 in the 7 bit size bytes
+        assert_eq!(id3_tag_size(b"ID3\x04\x00\x10\x00\x00\x01\x01"), Some(ID3_HEADER_SIZE + 0x81 + ID3_HEADER_SIZE));
+        assert_eq!(id3_tag_size(b"ID3\x04\x00\x00\x00\x00\x80\x00"), None);
+        assert_eq!(id3_tag_size(b"ID3\x04"), None);
+    }
+}
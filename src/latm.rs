@@ -0,0 +1,442 @@
+use std::fmt::{self, Display};
+use std::io::{self, Read};
+
+use crate::asc::{AscError, AudioSpecificConfig, BitReader, BitWriter};
+use crate::framing::FrameReader;
+
+pub const LOAS_HEADER_SIZE: usize = 3;
+const SYNC_WORD: u32 = 0x2b7;
+// 13 bit audioMuxLengthBytes field
+pub const MAX_MUX_LENGTH: usize = 0x1fff;
+
+// The AudioSyncStream header in front of every AudioMuxElement in LOAS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoasHeader {
+    // of the AudioMuxElement following the header
+    pub mux_length: usize,
+}
+
+impl LoasHeader {
+    pub fn parse(data: &[u8]) -> Result<LoasHeader, LatmError> {
+        if data.len() < LOAS_HEADER_SIZE {
+            return Err(LatmError::Truncated);
+        }
+
+        // 11 bit syncword 0x2b7
+        if data[0] != 0x56 || data[1] & 0xe0 != 0xe0 {
+            return Err(LatmError::NoSyncword);
+        }
+
+        Ok(LoasHeader {
+            mux_length: ((data[1] as usize & 0x1f) << 8) | data[2] as usize,
+        })
+    }
+
+    // header included
+    pub fn frame_length(&self) -> usize {
+        LOAS_HEADER_SIZE + self.mux_length
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoasFrame {
+    pub header: LoasHeader,
+    // header included
+    pub data: Vec<u8>,
+}
+
+impl LoasFrame {
+    pub fn mux_element(&self) -> &[u8] {
+        &self.data[LOAS_HEADER_SIZE..]
+    }
+}
+
+// Splits a byte stream into LOAS frames, resynchronizing like
+// adts::AdtsFrames.
+pub struct LoasFrames<R> {
+    reader: FrameReader<R>,
+}
+
+impl<R: Read> LoasFrames<R> {
+    pub fn new(input: R) -> Self {
+        LoasFrames { reader: FrameReader::new(input) }
+    }
+
+    pub fn skipped_bytes(&self) -> u64 {
+        self.reader.skipped_bytes()
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader.into_inner()
+    }
+
+    fn next_frame(&mut self) -> io::Result<Option<LoasFrame>> {
+        let data = self.reader.next_frame(LOAS_HEADER_SIZE, |data| {
+            LoasHeader::parse(data).ok().map(|header| header.frame_length())
+        })?;
+
+        Ok(data.map(|data| LoasFrame {
+            header: LoasHeader::parse(&data).expect("checked by the frame reader"),
+            data,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for LoasFrames<R> {
+    type Item = io::Result<LoasFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamMuxConfig {
+    pub audio_mux_version: u8,
+    pub all_streams_same_time_framing: bool,
+    // access units of every layer per AudioMuxElement
+    pub num_sub_frames: usize,
+    pub layers: Vec<LatmLayer>,
+    pub other_data_bits: u32,
+    pub crc: Option<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatmLayer {
+    pub program: usize,
+    pub layer: usize,
+    pub config: AudioSpecificConfig,
+    // as it would be passed to Decoder::new_raw
+    pub audio_specific_config: Vec<u8>,
+    // 0 for AAC with a payload length per access unit, 1 for a fixed
+    // frame_length. The others are for CELP and HVXC.
+    pub frame_length_type: u8,
+    pub buffer_fullness: Option<u8>,
+    // in bytes, for frame_length_type 1
+    pub frame_length: Option<usize>,
+}
+
+impl StreamMuxConfig {
+    // The StreamMuxConfig of an AudioMuxElement with muxConfigPresent, as in
+    // LOAS and Transport::LatmMcp1, e.g. the bytes after a LoasHeader. None
+    // if the element reuses the previous config.
+    pub fn from_mux_element(element: &[u8]) -> Result<Option<StreamMuxConfig>, LatmError> {
+        let mut bits = BitReader::new(element);
+
+        // useSameStreamMux
+        if bits.read(1)? != 0 {
+            return Ok(None);
+        }
+
+        StreamMuxConfig::read(&mut bits).map(Some)
+    }
+
+    // A bare StreamMuxConfig, as signaled out of band for
+    // Transport::LatmMcp0.
+    pub fn parse(data: &[u8]) -> Result<StreamMuxConfig, LatmError> {
+        StreamMuxConfig::read(&mut BitReader::new(data))
+    }
+
+    // The configuration of the first program and layer, the one the
+    // decoder picks.
+    pub fn audio_specific_config(&self) -> Option<&[u8]> {
+        self.layers.first().map(|layer| &layer.audio_specific_config[..])
+    }
+
+    fn read(bits: &mut BitReader) -> Result<StreamMuxConfig, LatmError> {
+        let audio_mux_version = bits.read(1)? as u8;
+        let audio_mux_version_a = if audio_mux_version == 1 { bits.read(1)? } else { 0 };
+        if audio_mux_version_a != 0 {
+            return Err(LatmError::Unsupported);
+        }
+
+        if audio_mux_version == 1 {
+            // taraBufferFullness
+            latm_value(bits)?;
+        }
+
+        let all_streams_same_time_framing = bits.read(1)? != 0;
+        let num_sub_frames = bits.read(6)? as usize + 1;
+        let num_programs = bits.read(4)? as usize + 1;
+
+        let mut layers: Vec<LatmLayer> = Vec::new();
+        for program in 0..num_programs {
+            let num_layers = bits.read(3)? as usize + 1;
+
+            for layer in 0..num_layers {
+                let use_same_config = !layers.is_empty() && bits.read(1)? != 0;
+
+                let (config, audio_specific_config) = if use_same_config {
+                    let previous = &layers[layers.len() - 1];
+                    (previous.config, previous.audio_specific_config.clone())
+                } else if audio_mux_version == 0 {
+                    // the config isn't length prefixed, so its end is only
+                    // found by parsing all of it
+                    let mut start = bits.clone();
+                    let (config, complete) = AudioSpecificConfig::read(bits, false)?;
+                    if !complete {
+                        return Err(LatmError::Unsupported);
+                    }
+                    (config, start.bytes(bits.position() - start.position())?)
+                } else {
+                    let len = latm_value(bits)? as usize;
+                    let bytes = bits.bytes(len)?;
+                    (AudioSpecificConfig::parse(&bytes)?, bytes)
+                };
+
+                let frame_length_type = bits.read(3)? as u8;
+                let mut buffer_fullness = None;
+                let mut frame_length = None;
+                match frame_length_type {
+                    0 => {
+                        buffer_fullness = Some(bits.read(8)? as u8);
+                        let scalable_celp = matches!(config.aot, 6 | 20) &&
+                            layers.last().is_some_and(|previous| matches!(previous.config.aot, 8 | 24));
+                        if !all_streams_same_time_framing && scalable_celp {
+                            // coreFrameOffset
+                            bits.read(6)?;
+                        }
+                    }
+                    1 => frame_length = Some(bits.read(9)? as usize + 20),
+                    3..=5 => {
+                        bits.read(6)?;
+                    }
+                    6 | 7 => {
+                        bits.read(1)?;
+                    }
+                    _ => return Err(LatmError::Unsupported),
+                }
+
+                layers.push(LatmLayer {
+                    program,
+                    layer,
+                    config,
+                    audio_specific_config,
+                    frame_length_type,
+                    buffer_fullness,
+                    frame_length,
+                });
+            }
+        }
+
+        let mut other_data_bits = 0;
+        if bits.read(1)? != 0 {
+            if audio_mux_version == 1 {
+                other_data_bits = latm_value(bits)?;
+            } else {
+                loop {
+                    let escape = bits.read(1)? != 0;
+                    other_data_bits = (other_data_bits << 8) + bits.read(8)?;
+                    if !escape {
+                        break;
+                    }
+                }
+            }
+        }
+
+        let crc = if bits.read(1)? != 0 { Some(bits.read(8)? as u8) } else { None };
+
+        Ok(StreamMuxConfig {
+            audio_mux_version,
+            all_streams_same_time_framing,
+            num_sub_frames,
+            layers,
+            other_data_bits,
+            crc,
+        })
+    }
+}
+
+// Splits an AudioMuxElement with muxConfigPresent into its access units.
+// config is the StreamMuxConfig in effect and replaced when the element
+// carries a new one. Only a single program and layer with AAC style payload
+// lengths is supported, as sent by broadcast encoders.
+pub fn demux_element(element: &[u8], config: &mut Option<StreamMuxConfig>) -> Result<Vec<Vec<u8>>, LatmError> {
+    let mut bits = BitReader::new(element);
+
+    if bits.read(1)? == 0 {
+        *config = Some(StreamMuxConfig::read(&mut bits)?);
+    }
+    let config = config.as_ref().ok_or(LatmError::MissingConfig)?;
+
+    // with separate time framing the sub frames are interleaved differently
+    if config.layers.len() != 1 || config.layers[0].frame_length_type != 0 || !config.all_streams_same_time_framing {
+        return Err(LatmError::Unsupported);
+    }
+
+    let mut access_units = Vec::with_capacity(config.num_sub_frames);
+    for _ in 0..config.num_sub_frames {
+        // PayloadLengthInfo, continued while 255
+        let mut len = 0;
+        loop {
+            let tmp = bits.read(8)? as usize;
+            len += tmp;
+            if tmp != 255 {
+                break;
+            }
+        }
+
+        access_units.push(bits.bytes(len * 8)?);
+    }
+
+    Ok(access_units)
+}
+
+// An AudioSyncStream frame carrying one access unit, along with a
+// StreamMuxConfig (version 0, one program and layer) for config when
+// with_config is set. Those without it only work for decoders that have
+// already seen one.
+pub fn loas_frame(config: &AudioSpecificConfig, access_unit: &[u8], with_config: bool) -> Result<Vec<u8>, LatmError> {
+    let mut bits = BitWriter::default();
+    bits.write(SYNC_WORD, 11);
+    // audioMuxLengthBytes, filled in below
+    bits.write(0, 13);
+
+    // useSameStreamMux
+    bits.write(!with_config as u32, 1);
+    if with_config {
+        // audioMuxVersion, allStreamsSameTimeFraming, numSubFrames,
+        // numProgram and numLayer
+        bits.write(0, 1);
+        bits.write(1, 1);
+        bits.write(0, 6);
+        bits.write(0, 4);
+        bits.write(0, 3);
+
+        // without a length prefix a reader can't tell where backward
+        // compatible signaling would end
+        AudioSpecificConfig { backward_compatible: false, ..*config }.write(&mut bits)?;
+
+        // frameLengthType, latmBufferFullness (VBR), otherDataPresent and
+        // crcCheckPresent
+        bits.write(0, 3);
+        bits.write(0xff, 8);
+        bits.write(0, 1);
+        bits.write(0, 1);
+    }
+
+    let mut len = access_unit.len();
+    while len >= 255 {
+        bits.write(255, 8);
+        len -= 255;
+    }
+    bits.write(len as u32, 8);
+    bits.write_bytes(access_unit);
+
+    let mut frame = bits.finish();
+    let mux_length = frame.len() - LOAS_HEADER_SIZE;
+    if mux_length > MAX_MUX_LENGTH {
+        return Err(LatmError::TooLong(mux_length));
+    }
+    frame[1] |= (mux_length >> 8) as u8;
+    frame[2] = mux_length as u8;
+
+    Ok(frame)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LatmError {
+    Truncated,
+    NoSyncword,
+    Unsupported,
+    // an element reusing the previous StreamMuxConfig before any was seen
+    MissingConfig,
+    TooLong(usize),
+}
+
+impl Display for LatmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LatmError::Truncated => write!(f, "LATM data truncated"),
+            LatmError::NoSyncword => write!(f, "no LOAS syncword"),
+            LatmError::Unsupported => write!(f, "unsupported StreamMuxConfig"),
+            LatmError::MissingConfig => write!(f, "no StreamMuxConfig received yet"),
+            LatmError::TooLong(len) => write!(f, "AudioMuxElement of {} bytes too long for LOAS", len),
+        }
+    }
+}
+
+impl std::error::Error for LatmError {
+}
+
+impl From<AscError> for LatmError {
+    fn from(e: AscError) -> Self {
+        match e {
+            AscError::Truncated => LatmError::Truncated,
+            AscError::Unsupported => LatmError::Unsupported,
+        }
+    }
+}
+
+// LatmGetValue, bytesForValue followed by that many bytes plus one.
+fn latm_value(bits: &mut BitReader) -> Result<u32, AscError> {
+    let bytes = bits.read(2)? + 1;
+
+    let mut value = 0;
+    for _ in 0..bytes {
+        value = (value << 8) | bits.read(8)?;
+    }
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> AudioSpecificConfig {
+        AudioSpecificConfig::new(48000, 2)
+    }
+
+    #[test]
+    fn loas_frame_round_trip() {
+        let access_unit: Vec<u8> = (0..300).map(|index| index as u8).collect();
+        let data = loas_frame(&config(), &access_unit, true).unwrap();
+
+        let header = LoasHeader::parse(&data).unwrap();
+        assert_eq!(header.frame_length(), data.len());
+
+        let mut mux_config = None;
+        let access_units = demux_element(&data[LOAS_HEADER_SIZE..], &mut mux_config).unwrap();
+        assert_eq!(access_units, [access_unit]);
+
+        let mux_config = mux_config.unwrap();
+        assert!(mux_config.all_streams_same_time_framing);
+        assert_eq!(mux_config.num_sub_frames, 1);
+        assert_eq!(mux_config.layers.len(), 1);
+        assert_eq!(mux_config.layers[0].config, config());
+        assert_eq!(mux_config.audio_specific_config(), Some(&config().to_bytes().unwrap()[..]));
+    }
+
+    #[test]
+    fn demux_with_previous_config() {
+        let with_config = loas_frame(&config(), &[1; 10], true).unwrap();
+        let without_config = loas_frame(&config(), &[2; 20], false).unwrap();
+
+        let mut mux_config = None;
+        assert_eq!(demux_element(&without_config[LOAS_HEADER_SIZE..], &mut mux_config), Err(LatmError::MissingConfig));
+        demux_element(&with_config[LOAS_HEADER_SIZE..], &mut mux_config).unwrap();
+        assert_eq!(demux_element(&without_config[LOAS_HEADER_SIZE..], &mut mux_config).unwrap(), [vec![2; 20]]);
+    }
+
+    #[test]
+    fn demux_rejects_separate_time_framing() {
+        let mut data = loas_frame(&config(), &[1; 10], true).unwrap();
+        // allStreamsSameTimeFraming, after useSameStreamMux and audioMuxVersion
+        data[LOAS_HEADER_SIZE] &= !0x20;
+
+        let mut mux_config = None;
+        assert_eq!(demux_element(&data[LOAS_HEADER_SIZE..], &mut mux_config), Err(LatmError::Unsupported));
+    }
+
+    #[test]
+    fn frames_resync() {
+        let mut data = vec![0x56, 0x00, 0x12];
+        data.extend(loas_frame(&config(), &[1; 10], true).unwrap());
+        data.extend(loas_frame(&config(), &[2; 20], false).unwrap());
+
+        let mut frames = LoasFrames::new(&data[..]);
+        assert_eq!((&mut frames).count(), 2);
+        assert_eq!(frames.skipped_bytes(), 3);
+    }
+}
@@ -1,2 +1,18 @@
+pub mod adts;
+pub mod asc;
 pub mod enc;
+#[cfg(feature = "native")]
 pub mod dec;
+mod framing;
+pub mod latm;
+#[cfg(feature = "native")]
+pub mod lib_info;
+#[cfg(feature = "native")]
+pub mod probe;
+pub mod remux;
+#[cfg(feature = "wav")]
+pub mod wav;
+#[cfg(feature = "mock")]
+pub mod mock;
+#[cfg(feature = "tokio")]
+pub mod async_decoder;
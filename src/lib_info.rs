@@ -0,0 +1,59 @@
+use std::ffi::CStr;
+use std::mem;
+use std::os::raw::c_char;
+
+use fdk_aac_sys as sys;
+
+pub use sys::FDK_MODULE_ID as ModuleId;
+
+#[derive(Debug, Clone)]
+pub struct LibInfo {
+    pub module_id: ModuleId,
+    pub title: String,
+    pub version: String,
+    pub build_date: String,
+    pub build_time: String,
+    pub flags: u32,
+}
+
+impl LibInfo {
+    // capability flags are the sys::CAPF_* constants of the respective module
+    pub fn has_capability(&self, flag: u32) -> bool {
+        self.flags & flag == flag
+    }
+}
+
+pub fn find(infos: &[LibInfo], module_id: ModuleId) -> Option<&LibInfo> {
+    infos.iter().find(|info| info.module_id == module_id)
+}
+
+fn string(ptr: *const c_char) -> String {
+    if ptr.is_null() {
+        return String::new();
+    }
+
+    unsafe { CStr::from_ptr(ptr) }.to_string_lossy().into_owned()
+}
+
+// The library fills the first unused entries of a table sized for all
+// modules, unused entries are marked with FDK_NONE.
+pub(crate) fn collect<E>(fill: impl FnOnce(*mut sys::LIB_INFO) -> Result<(), E>) -> Result<Vec<LibInfo>, E> {
+    let mut table: [sys::LIB_INFO; sys::FDK_MODULE_ID_FDK_MODULE_LAST as usize] = unsafe { mem::zeroed() };
+    for info in table.iter_mut() {
+        info.module_id = sys::FDK_MODULE_ID_FDK_NONE;
+    }
+
+    fill(table.as_mut_ptr())?;
+
+    Ok(table.iter()
+        .filter(|info| info.module_id != sys::FDK_MODULE_ID_FDK_NONE)
+        .map(|info| LibInfo {
+            module_id: info.module_id,
+            title: string(info.title),
+            version: string(info.versionStr.as_ptr()),
+            build_date: string(info.build_date),
+            build_time: string(info.build_time),
+            flags: info.flags,
+        })
+        .collect())
+}
@@ -0,0 +1,194 @@
+use std::io::Write;
+
+use crate::enc::{self, AacEncode, AudioObjectType, BitRate, EncodeInfo, EncoderError, EncoderInfo, EncoderParams};
+
+// 6144 bits per channel, the AAC limit for a single access unit
+const MAX_CHANNEL_BYTES: usize = 768;
+
+// Stands in for Encoder without calling into libfdk-aac. Every access unit
+// starts with the big endian frame index followed by zero padding, sized
+// from the configured bitrate.
+#[derive(Debug)]
+pub struct MockEncoder {
+    params: EncoderParams,
+    frame_length: usize,
+    channels: usize,
+    pending: usize,
+    frames: u32,
+}
+
+impl MockEncoder {
+    pub fn new(params: EncoderParams) -> Result<Self, EncoderError> {
+        enc::validate(&params)?;
+
+        let core_frame_length = match params.audio_object_type {
+            AudioObjectType::AacLd | AudioObjectType::AacEld => params.frame_length.unwrap_or(512),
+            _ => params.frame_length.unwrap_or(1024),
+        } as usize;
+
+        // SBR runs the core coder at half the input rate
+        let frame_length = match params.audio_object_type {
+            AudioObjectType::HeAac | AudioObjectType::HeAacV2 => core_frame_length * 2,
+            _ => core_frame_length,
+        };
+
+        Ok(MockEncoder {
+            channels: params.channels.channels(),
+            params,
+            frame_length,
+            pending: 0,
+            frames: 0,
+        })
+    }
+
+    pub fn frames_encoded(&self) -> u32 {
+        self.frames
+    }
+
+    fn access_unit_size(&self) -> usize {
+        let bit_rate = match self.params.bit_rate {
+            BitRate::Cbr(bit_rate) => bit_rate as usize,
+            _ => 64000 * self.channels,
+        };
+
+        (bit_rate * self.frame_length / self.params.sample_rate as usize / 8).clamp(4, MAX_CHANNEL_BYTES * self.channels)
+    }
+
+    fn write_access_unit(&mut self, out: &mut [u8]) -> Result<usize, EncoderError> {
+        let size = self.access_unit_size();
+        if out.len() < size {
+            return Err(EncoderError::InvalidParams("Output buffer too small for access unit."));
+        }
+
+        out[..4].copy_from_slice(&self.frames.to_be_bytes());
+        for byte in &mut out[4..size] {
+            *byte = 0;
+        }
+
+        self.frames += 1;
+        self.pending = 0;
+        Ok(size)
+    }
+}
+
+impl AacEncode for MockEncoder {
+    fn encode_frame(&mut self, pcm: &[i16], out: &mut [u8]) -> Result<EncodeInfo, EncoderError> {
+        let frame_samples = self.frame_length * self.channels;
+        let input_consumed = pcm.len().min(frame_samples - self.pending);
+        self.pending += input_consumed;
+
+        let output_size = if self.pending == frame_samples {
+            self.write_access_unit(out)?
+        } else {
+            0
+        };
+
+        Ok(EncodeInfo { input_consumed, output_size })
+    }
+
+    fn flush(&mut self, output: &mut dyn Write) -> Result<EncodeInfo, EncoderError> {
+        if self.pending == 0 {
+            return Ok(EncodeInfo { input_consumed: 0, output_size: 0 });
+        }
+
+        let mut output_buffer = vec![0; self.access_unit_size()];
+        let output_size = self.write_access_unit(&mut output_buffer)?;
+        output.write_all(&output_buffer)?;
+
+        Ok(EncodeInfo { input_consumed: 0, output_size })
+    }
+
+    fn info(&self) -> Result<EncoderInfo, EncoderError> {
+        Ok(EncoderInfo {
+            frame_length: self.frame_length,
+            input_channels: self.channels,
+            delay: 0,
+            delay_core: 0,
+            max_out_buf_bytes: MAX_CHANNEL_BYTES * self.channels,
+            max_anc_bytes: 0,
+            config: Vec::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::enc::ChannelMode;
+
+    #[test]
+    fn encode_frame() {
+        let mut encoder = MockEncoder::new(EncoderParams::default()).unwrap();
+        let info = encoder.info().unwrap();
+        assert_eq!(info.frame_length, 1024);
+        assert_eq!(info.input_channels, 2);
+
+        let mut out = vec![0xff; info.max_out_buf_bytes];
+        let pcm = vec![0; 2048 + 100];
+
+        let first = encoder.encode_frame(&pcm[..1000], &mut out).unwrap();
+        assert_eq!((first.input_consumed, first.output_size), (1000, 0));
+
+        // only what completes the frame is taken
+        let second = encoder.encode_frame(&pcm[1000..], &mut out).unwrap();
+        assert_eq!(second.input_consumed, 2048 - 1000);
+        assert_eq!(second.output_size, 128000 * 1024 / 44100 / 8);
+        assert_eq!(out[..4], 0u32.to_be_bytes());
+        assert!(out[4..second.output_size].iter().all(|byte| *byte == 0));
+
+        encoder.encode_frame(&pcm[..2048], &mut out).unwrap();
+        assert_eq!(out[..4], 1u32.to_be_bytes());
+        assert_eq!(encoder.frames_encoded(), 2);
+    }
+
+    #[test]
+    fn sbr_doubles_the_frame_length() {
+        let params = EncoderParams::builder()
+            .audio_object_type(AudioObjectType::HeAac)
+            .bit_rate(BitRate::Cbr(64000))
+            .build()
+            .unwrap();
+        assert_eq!(MockEncoder::new(params).unwrap().info().unwrap().frame_length, 2048);
+    }
+
+    #[test]
+    fn flush() {
+        let mut encoder = MockEncoder::new(EncoderParams::default()).unwrap();
+        let mut output = Vec::new();
+
+        let info = encoder.flush(&mut output).unwrap();
+        assert_eq!((info.input_consumed, info.output_size), (0, 0));
+        assert!(output.is_empty());
+
+        let mut out = vec![0; 2048];
+        encoder.encode_frame(&[0; 100], &mut out).unwrap();
+        let info = encoder.flush(&mut output).unwrap();
+        assert_eq!(info.output_size, output.len());
+        assert_eq!(output[..4], 0u32.to_be_bytes());
+        assert_eq!(encoder.frames_encoded(), 1);
+
+        // nothing left after the flush
+        assert_eq!(encoder.flush(&mut output).unwrap().output_size, 0);
+    }
+
+    #[test]
+    fn output_buffer_too_small() {
+        let mut encoder = MockEncoder::new(EncoderParams::default()).unwrap();
+        let mut out = [0; 16];
+        assert!(matches!(encoder.encode_frame(&[0; 2048], &mut out), Err(EncoderError::InvalidParams(_))));
+    }
+
+    #[test]
+    fn rejects_invalid_params() {
+        let params = EncoderParams {
+            audio_object_type: AudioObjectType::HeAacV2,
+            channels: ChannelMode::Mono,
+            bit_rate: BitRate::Cbr(32000),
+            ..EncoderParams::default()
+        };
+        assert!(matches!(MockEncoder::new(params), Err(EncoderError::InvalidParams(_))));
+
+        let params = EncoderParams { sample_rate: 44000, ..EncoderParams::default() };
+        assert!(matches!(MockEncoder::new(params), Err(EncoderError::InvalidParams(_))));
+    }
+}
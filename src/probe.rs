@@ -0,0 +1,164 @@
+use std::io::{self, Cursor, Read};
+
+use fdk_aac_sys as sys;
+
+use crate::adts::{self, AdtsHeader};
+use crate::dec::{self, Decoder, Transport};
+use crate::framing::{id3_tag_size, ID3_HEADER_SIZE};
+use crate::latm::{self, LoasHeader};
+
+// Enough for a few frames of any sensible bit rate.
+const PROBE_SIZE: usize = 16 * 1024;
+
+const ADIF_MAGIC: &[u8] = b"ADIF";
+
+// Implicitly signaled SBR only shows up once a frame carrying it was
+// decoded, which usually is the first one.
+const PROBE_FRAMES: usize = 8;
+
+// aot is the core audio object type, sample_rate that of the output, so
+// twice core_sample_rate with dual-rate SBR. bit_rate is averaged over the
+// probed frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamDescription {
+    pub transport: Transport,
+    pub aot: i32,
+    pub sbr: bool,
+    pub ps: bool,
+    pub sample_rate: u32,
+    pub core_sample_rate: u32,
+    pub channels: usize,
+    pub channel_config: u32,
+    pub frame_size: usize,
+    pub bit_rate: u32,
+}
+
+pub type ProbedInput<R> = io::Chain<Cursor<Vec<u8>>, R>;
+
+// Guesses the transport from the start of a stream, skipping an ID3v2 tag.
+// ADIF is recognized by its magic, ADTS and LOAS by a syncword followed by
+// another one a frame length later, unless the first frame runs past the
+// end of data. Raw access units and LATM without LOAS carry no syncword
+// and no config, they can't be told apart from noise and a decoder for
+// them needs the AudioSpecificConfig from the container anyway, so for
+// them this returns None instead of guessing Transport::Raw.
+pub fn detect_transport(data: &[u8]) -> Option<Transport> {
+    let start = id3_tag_size(data).unwrap_or(0).min(data.len());
+    if data[start..].starts_with(ADIF_MAGIC) {
+        return Some(Transport::Adif);
+    }
+
+    for pos in start..data.len() {
+        for transport in [Transport::Adts, Transport::Loas] {
+            let len = match frame_length(&data[pos..], transport) {
+                Some(len) => len,
+                None => continue,
+            };
+
+            let next = pos + len;
+            let confirmed = match data.get(next..) {
+                Some(rest) if rest.len() >= header_size(transport) => frame_length(rest, transport).is_some(),
+                _ => pos == start,
+            };
+            if confirmed {
+                return Some(transport);
+            }
+        }
+    }
+
+    None
+}
+
+// Reads the start of input to detect the transport and opens a decoder for
+// it. A leading ID3v2 tag is skipped without buffering it, the probed bytes
+// after it are put back in front of the returned input, so it can be handed
+// to Decoder::frames as is.
+pub fn open<R: Read>(mut input: R) -> io::Result<(Decoder, ProbedInput<R>)> {
+    let mut data = vec![0; ID3_HEADER_SIZE];
+    let len = read_full(&mut input, &mut data)?;
+
+    // the tag size comes from the stream, so it's never allocated
+    let len = match id3_tag_size(&data[..len]) {
+        Some(tag_size) => {
+            io::copy(&mut (&mut input).take((tag_size - len) as u64), &mut io::sink())?;
+            0
+        }
+        None => len,
+    };
+
+    data.resize(len + PROBE_SIZE, 0);
+    let len = len + read_full(&mut input, &mut data[len..])?;
+    data.truncate(len);
+
+    let transport = detect_transport(&data)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no ADIF header or ADTS or LOAS syncword found"))?;
+    let decoder = Decoder::new(transport).map_err(dec::decoder_error)?;
+
+    Ok((decoder, Cursor::new(data).chain(input)))
+}
+
+// Detects the transport and decodes the first few frames to describe the
+// stream.
+pub fn probe<R: Read>(input: R) -> io::Result<StreamDescription> {
+    let (mut decoder, input) = open(input)?;
+
+    let mut frames = 0;
+    for frame in decoder.frames(input).take(PROBE_FRAMES) {
+        frame?;
+        frames += 1;
+    }
+    if frames == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "no frame decoded"));
+    }
+
+    let info = decoder.stream_info();
+    let stats = decoder.stats();
+
+    // frames_decoded leaves out a flush at the end of a short stream
+    let samples = stats.frames_decoded * info.frame_size as u64;
+    let bit_rate = (stats.total_bytes * 8 * info.sample_rate as u64)
+        .checked_div(samples)
+        .unwrap_or(info.bit_rate as u64);
+
+    Ok(StreamDescription {
+        transport: decoder.transport(),
+        aot: info.aot,
+        sbr: info.ext_aot == sys::AUDIO_OBJECT_TYPE_AOT_SBR || info.ext_aot == sys::AUDIO_OBJECT_TYPE_AOT_PS,
+        ps: info.ext_aot == sys::AUDIO_OBJECT_TYPE_AOT_PS,
+        sample_rate: info.sample_rate,
+        core_sample_rate: info.aac_sample_rate,
+        channels: info.channels,
+        channel_config: info.channel_config,
+        frame_size: info.frame_size,
+        bit_rate: bit_rate as u32,
+    })
+}
+
+fn header_size(transport: Transport) -> usize {
+    match transport {
+        Transport::Adts => adts::HEADER_SIZE,
+        _ => latm::LOAS_HEADER_SIZE,
+    }
+}
+
+// Total length of the frame starting at data, header included.
+fn frame_length(data: &[u8], transport: Transport) -> Option<usize> {
+    match transport {
+        Transport::Adts => AdtsHeader::parse(data).ok().map(|header| header.frame_length),
+        _ => LoasHeader::parse(data).ok().map(|header| header.frame_length()),
+    }
+}
+
+fn read_full<R: Read>(input: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match input.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(read) => len += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(len)
+}
@@ -0,0 +1,278 @@
+use std::collections::VecDeque;
+use std::io::{self, Read};
+
+use crate::adts::{AdtsError, AdtsFrames, AdtsHeader};
+use crate::asc::AudioSpecificConfig;
+use crate::latm::{self, LoasFrames, StreamMuxConfig};
+
+// Strips the ADTS headers off a stream, leaving the raw access units as
+// stored in MP4 and decoded by Decoder::new_raw with config().
+pub struct AdtsToRaw<R> {
+    frames: AdtsFrames<R>,
+    header: Option<AdtsHeader>,
+    config: Vec<u8>,
+}
+
+impl<R: Read> AdtsToRaw<R> {
+    pub fn new(input: R) -> Self {
+        AdtsToRaw {
+            frames: AdtsFrames::new(input),
+            header: None,
+            config: Vec::new(),
+        }
+    }
+
+    // The AudioSpecificConfig synthesized from the first header, empty
+    // before the first access unit was read. SBR and PS aren't signaled in
+    // ADTS and thus stay implicit.
+    pub fn config(&self) -> &[u8] {
+        &self.config
+    }
+
+    pub fn skipped_bytes(&self) -> u64 {
+        self.frames.skipped_bytes()
+    }
+
+    fn next_access_unit(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut frame = match self.frames.next() {
+            Some(frame) => frame?,
+            None => return Ok(None),
+        };
+
+        let header = frame.header;
+        if header.raw_data_blocks != 1 {
+            return Err(invalid_data("ADTS frames with several raw data blocks can't be split"));
+        }
+
+        match self.header {
+            None => {
+                self.config = header.audio_specific_config().map_err(invalid_data)?.to_bytes().map_err(invalid_data)?;
+                self.header = Some(header);
+            }
+            // one config has to fit the whole stream
+            Some(first) if !same_config(&first, &header) => {
+                return Err(invalid_data("ADTS stream configuration changed"));
+            }
+            Some(_) => {}
+        }
+
+        frame.data.drain(..header.header_length());
+        Ok(Some(frame.data))
+    }
+}
+
+impl<R: Read> Iterator for AdtsToRaw<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_access_unit().transpose()
+    }
+}
+
+// Puts ADTS headers in front of raw access units, the reverse of AdtsToRaw.
+pub struct RawToAdts {
+    asc: Vec<u8>,
+    config: AudioSpecificConfig,
+    header: AdtsHeader,
+}
+
+impl RawToAdts {
+    // config is the AudioSpecificConfig of the access units, e.g.
+    // EncoderInfo::config or from the MP4 esds box.
+    pub fn new(config: &[u8]) -> Result<Self, AdtsError> {
+        Ok(RawToAdts {
+            asc: config.to_vec(),
+            config: AudioSpecificConfig::parse(config)?,
+            header: AdtsHeader::from_config(config, 0)?,
+        })
+    }
+
+    pub fn config(&self) -> &AudioSpecificConfig {
+        &self.config
+    }
+
+    pub fn frame(&self, access_unit: &[u8]) -> Result<Vec<u8>, AdtsError> {
+        let header = self.header.with_payload_length(access_unit.len())?;
+
+        let mut frame = Vec::with_capacity(header.frame_length);
+        frame.extend_from_slice(&header.to_bytes()?);
+        frame.extend_from_slice(access_unit);
+        Ok(frame)
+    }
+}
+
+// Rewraps an ADTS stream into LOAS frames, one access unit each.
+pub struct AdtsToLoas<R> {
+    raw: AdtsToRaw<R>,
+    config: Option<AudioSpecificConfig>,
+    config_interval: usize,
+    // since the last StreamMuxConfig
+    frames: usize,
+}
+
+impl<R: Read> AdtsToLoas<R> {
+    pub fn new(input: R) -> Self {
+        AdtsToLoas {
+            raw: AdtsToRaw::new(input),
+            config: None,
+            config_interval: 1,
+            frames: 0,
+        }
+    }
+
+    // The StreamMuxConfig is repeated every this many frames so decoders
+    // can join mid-stream, by default in every frame.
+    pub fn config_interval(mut self, frames: usize) -> Self {
+        self.config_interval = frames.max(1);
+        self
+    }
+
+    pub fn skipped_bytes(&self) -> u64 {
+        self.raw.skipped_bytes()
+    }
+
+    fn next_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let access_unit = match self.raw.next() {
+            Some(access_unit) => access_unit?,
+            None => return Ok(None),
+        };
+
+        if self.config.is_none() {
+            self.config = Some(AudioSpecificConfig::parse(self.raw.config()).map_err(invalid_data)?);
+        }
+        let config = self.config.as_ref().expect("set above");
+
+        let with_config = self.frames == 0;
+        self.frames = (self.frames + 1) % self.config_interval;
+
+        latm::loas_frame(config, &access_unit, with_config)
+            .map(Some)
+            .map_err(invalid_data)
+    }
+}
+
+impl<R: Read> Iterator for AdtsToLoas<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
+    }
+}
+
+// Rewraps a LOAS stream into ADTS frames, see latm::demux_element for what
+// is supported. Frames before the first StreamMuxConfig are dropped.
+pub struct LoasToAdts<R> {
+    frames: LoasFrames<R>,
+    mux_config: Option<StreamMuxConfig>,
+    adts: Option<RawToAdts>,
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl<R: Read> LoasToAdts<R> {
+    pub fn new(input: R) -> Self {
+        LoasToAdts {
+            frames: LoasFrames::new(input),
+            mux_config: None,
+            adts: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn skipped_bytes(&self) -> u64 {
+        self.frames.skipped_bytes()
+    }
+
+    fn next_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        while self.pending.is_empty() {
+            let frame = match self.frames.next() {
+                Some(frame) => frame?,
+                None => return Ok(None),
+            };
+
+            let access_units = match latm::demux_element(frame.mux_element(), &mut self.mux_config) {
+                Ok(access_units) => access_units,
+                Err(latm::LatmError::MissingConfig) => continue,
+                Err(e) => return Err(invalid_data(e)),
+            };
+
+            let config = self.mux_config.as_ref()
+                .and_then(|mux_config| mux_config.audio_specific_config())
+                .expect("set by demux_element");
+            if self.adts.as_ref().map(|adts| adts.asc.as_slice()) != Some(config) {
+                self.adts = Some(RawToAdts::new(config).map_err(invalid_data)?);
+            }
+
+            let adts = self.adts.as_ref().expect("set above");
+            for access_unit in access_units {
+                self.pending.push_back(adts.frame(&access_unit).map_err(invalid_data)?);
+            }
+        }
+
+        Ok(self.pending.pop_front())
+    }
+}
+
+impl<R: Read> Iterator for LoasToAdts<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
+    }
+}
+
+fn same_config(a: &AdtsHeader, b: &AdtsHeader) -> bool {
+    a.profile == b.profile &&
+        a.sampling_frequency_index == b.sampling_frequency_index &&
+        a.channel_config == b.channel_config
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: [u8; 2] = [0x11, 0x90];
+
+    fn access_units() -> Vec<Vec<u8>> {
+        (1..=5).map(|index| vec![index as u8; index * 50]).collect()
+    }
+
+    fn adts_stream() -> Vec<u8> {
+        let adts = RawToAdts::new(&CONFIG).unwrap();
+        access_units().iter().flat_map(|access_unit| adts.frame(access_unit).unwrap()).collect()
+    }
+
+    #[test]
+    fn adts_to_raw() {
+        let data = adts_stream();
+        let mut raw = AdtsToRaw::new(&data[..]);
+        let access_units: Vec<Vec<u8>> = (&mut raw).map(|access_unit| access_unit.unwrap()).collect();
+
+        assert_eq!(access_units, self::access_units());
+        assert_eq!(raw.config(), CONFIG);
+    }
+
+    #[test]
+    fn adts_to_loas_and_back() {
+        let data = adts_stream();
+        let loas: Vec<u8> = AdtsToLoas::new(&data[..])
+            .config_interval(2)
+            .flat_map(|frame| frame.unwrap())
+            .collect();
+
+        let adts: Vec<u8> = LoasToAdts::new(&loas[..]).flat_map(|frame| frame.unwrap()).collect();
+        assert_eq!(adts, data);
+    }
+
+    #[test]
+    fn adts_config_change() {
+        let mut data = adts_stream();
+        data.extend(RawToAdts::new(&[0x12, 0x10]).unwrap().frame(&[1; 10]).unwrap());
+
+        let result: io::Result<Vec<Vec<u8>>> = AdtsToRaw::new(&data[..]).collect();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,86 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::enc::{EncodeInfo, Encoder, EncoderError, EncoderParams, Sample, Transport};
+
+// Encodes a WAV file to an ADTS file. The WAV sample rate and channel count
+// have to match the encoder params.
+pub fn encode_wav_file<P: AsRef<Path>, Q: AsRef<Path>>(path_in: P, path_out: Q, params: EncoderParams) -> Result<EncodeInfo, EncoderError> {
+    let mut reader = hound::WavReader::open(path_in).map_err(wav_error)?;
+    let spec = reader.spec();
+
+    if spec.sample_rate != params.sample_rate {
+        return Err(EncoderError::InvalidParams("WAV sample rate does not match the encoder sample rate."));
+    }
+
+    if spec.channels as usize != params.channels.channels() {
+        return Err(EncoderError::InvalidParams("WAV channel count does not match the encoder channel mode."));
+    }
+
+    if params.transport != Transport::Adts {
+        return Err(EncoderError::InvalidParams("WAV files can only be encoded to ADTS."));
+    }
+
+    let mut encoder = Encoder::new(params)?;
+    let info = encoder.info()?;
+
+    let mut output = BufWriter::new(File::create(path_out)?);
+    let mut output_buffer = vec![0; info.max_out_buf_bytes];
+    let chunk_len = info.input_channels * info.frame_length;
+
+    let mut total = EncodeInfo {
+        input_consumed: 0,
+        output_size: 0,
+    };
+
+    match spec.sample_format {
+        hound::SampleFormat::Float => {
+            let samples = reader.samples::<f32>();
+            encode_chunks(&mut encoder, samples, chunk_len, &mut output_buffer, &mut output, &mut total)?;
+        }
+        hound::SampleFormat::Int => {
+            // scale up to full range 32 bit samples
+            let shift = 32 - spec.bits_per_sample as u32;
+            let samples = reader.samples::<i32>().map(|sample| sample.map(|sample| sample << shift));
+            encode_chunks(&mut encoder, samples, chunk_len, &mut output_buffer, &mut output, &mut total)?;
+        }
+    }
+
+    total.output_size += encoder.flush(&mut output)?.output_size;
+    output.flush()?;
+
+    Ok(total)
+}
+
+fn encode_chunks<S, I, W>(encoder: &mut Encoder, mut samples: I, chunk_len: usize, output_buffer: &mut [u8], output: &mut W, total: &mut EncodeInfo) -> Result<(), EncoderError>
+    where S: Sample, I: Iterator<Item = Result<S, hound::Error>>, W: Write
+{
+    let mut chunk = Vec::with_capacity(chunk_len);
+    loop {
+        chunk.clear();
+        for sample in samples.by_ref().take(chunk_len) {
+            chunk.push(sample.map_err(wav_error)?);
+        }
+
+        if chunk.is_empty() {
+            return Ok(());
+        }
+
+        let mut offset = 0;
+        while offset < chunk.len() {
+            let frame = encoder.encode_frame(&chunk[offset..], output_buffer)?;
+            output.write_all(&output_buffer[0..frame.output_size])?;
+            offset += frame.input_consumed;
+            total.input_consumed += frame.input_consumed;
+            total.output_size += frame.output_size;
+        }
+    }
+}
+
+fn wav_error(err: hound::Error) -> EncoderError {
+    match err {
+        hound::Error::IoError(err) => EncoderError::Io(err),
+        err => EncoderError::Io(io::Error::new(io::ErrorKind::InvalidData, err)),
+    }
+}